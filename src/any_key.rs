@@ -0,0 +1,289 @@
+use anyhow::{Error, Result};
+
+use crate::key_util::*;
+use crate::{
+    BlindIndexKey, DeterministicAeadKey, DeterministicEncryptionSymmetricKey256,
+    FormatPreservingKey, KeyShare, OpaqueKey, PassphraseParams, SnowKeyPair, SnowPrivateKey,
+    SnowPsk, SnowPublicKey, SymmetricKey, WideBlockEncryptionKey256, WrappedKey,
+};
+
+macro_rules! any_key {
+    ($($variant:ident($key:ty)),* $(,)?) => {
+        /// Any of the key types this crate knows how to serialize, picked by
+        /// the header of the key string.
+        #[derive(Clone)]
+        #[allow(clippy::large_enum_variant)]
+        pub enum AnyKey {
+            $($variant($key),)*
+        }
+
+        impl AnyKey {
+            /// Headers of every key type, in the order they are tried.
+            pub const HEADERS: &'static [&'static str] = &[$(<$key>::HEADER,)*];
+
+            pub fn header(&self) -> &'static str {
+                match self {
+                    $(AnyKey::$variant(_) => <$key>::HEADER,)*
+                }
+            }
+
+            pub fn key_bytes(&self) -> Vec<u8> {
+                match self {
+                    $(AnyKey::$variant(key) => key.key_bytes(),)*
+                }
+            }
+
+            pub fn serialize_to_string(&self) -> String {
+                match self {
+                    $(AnyKey::$variant(key) => key.serialize_to_string(),)*
+                }
+            }
+
+            pub fn serialize_with(&self, encoding: KeyEncoding) -> String {
+                match self {
+                    $(AnyKey::$variant(key) => key.serialize_with(encoding),)*
+                }
+            }
+
+            pub fn fingerprint(&self) -> String {
+                match self {
+                    $(AnyKey::$variant(key) => key.fingerprint(),)*
+                }
+            }
+        }
+
+        impl std::str::FromStr for AnyKey {
+            type Err = anyhow::Error;
+            fn from_str(data: &str) -> Result<AnyKey> {
+                let data = data.trim();
+                $(
+                    if has_header(data, <$key>::HEADER) {
+                        return Ok(AnyKey::$variant(data.parse()?));
+                    }
+                )*
+                Err(KeyParseError::WrongHeader {
+                    expected: "for any known key type".to_string(),
+                }
+                .into())
+            }
+        }
+
+        $(
+            impl From<$key> for AnyKey {
+                fn from(key: $key) -> AnyKey {
+                    AnyKey::$variant(key)
+                }
+            }
+
+            impl TryFrom<AnyKey> for $key {
+                type Error = anyhow::Error;
+                fn try_from(key: AnyKey) -> Result<$key> {
+                    match key {
+                        AnyKey::$variant(key) => Ok(key),
+                        other => Err(Error::msg(format!(
+                            "expected a {} key, got {}",
+                            header_type(<$key>::HEADER),
+                            other.type_name()
+                        ))),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+any_key!(
+    Symmetric(SymmetricKey),
+    Deterministic(DeterministicEncryptionSymmetricKey256),
+    DeterministicAead(DeterministicAeadKey),
+    WideBlock(WideBlockEncryptionKey256),
+    FormatPreserving(FormatPreservingKey),
+    BlindIndex(BlindIndexKey),
+    Opaque(OpaqueKey),
+    SnowKeyPair(SnowKeyPair),
+    SnowPublicKey(SnowPublicKey),
+    SnowPrivateKey(SnowPrivateKey),
+    SnowPsk(SnowPsk),
+    Wrapped(WrappedKey),
+    Share(KeyShare),
+);
+
+crate::serde_support::derive_serde!(AnyKey, AnyKeyVisitor);
+
+impl AnyKey {
+    /// Short name of the key type, e.g. `sym` or `snow_key_pair`.
+    pub fn type_name(&self) -> &'static str {
+        header_type(self.header())
+    }
+
+    /// Header version, e.g. `eseb0`.
+    pub fn version(&self) -> &'static str {
+        header_version(self.header())
+    }
+
+    /// The header that `data` claims to have, whether or not the rest of it
+    /// parses.
+    pub fn detect_header(data: &str) -> Option<&'static str> {
+        let data = data.trim();
+        Self::HEADERS
+            .iter()
+            .copied()
+            .find(|header| has_header(data, header))
+    }
+
+    /// The public half, for key types that have one.
+    pub fn public_key(&self) -> Option<SnowPublicKey> {
+        match self {
+            AnyKey::SnowKeyPair(key) => Some(key.to_public()),
+            AnyKey::SnowPublicKey(key) => Some(key.clone()),
+            _ => None,
+        }
+    }
+
+    /// The key as a list of words, for paper backups.
+    pub fn to_mnemonic(&self) -> Result<String> {
+        crate::mnemonic::encode(self.header(), &self.key_bytes())
+    }
+
+    /// Parses the output of `to_mnemonic`.
+    pub fn from_mnemonic(mnemonic: &str) -> Result<AnyKey> {
+        crate::mnemonic::decode(mnemonic)?.parse()
+    }
+
+    pub fn wrap(&self, passphrase: &[u8], params: &PassphraseParams) -> Result<WrappedKey> {
+        WrappedKey::wrap_string(&self.serialize_to_string(), passphrase, params)
+    }
+
+    /// See `KeyShare::split`.
+    pub fn split(&self, threshold: u8, shares: u8) -> Result<Vec<KeyShare>> {
+        KeyShare::split_string(&self.serialize_to_string(), threshold, shares)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    fn round_trip(key: AnyKey, header: &str) {
+        let ser = key.serialize_to_string();
+        assert!(ser.starts_with(header));
+        assert_eq!(AnyKey::detect_header(&ser), Some(key.header()));
+        let deser = AnyKey::from_str(&ser).unwrap();
+        assert_eq!(deser.header(), header);
+        assert_eq!(deser.key_bytes(), key.key_bytes());
+        assert_eq!(deser.fingerprint(), key.fingerprint());
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let pair = SnowKeyPair::gen_key().unwrap();
+        round_trip(SymmetricKey::gen_key().unwrap().into(), "eseb0::sym::");
+        round_trip(
+            DeterministicEncryptionSymmetricKey256::gen_key()
+                .unwrap()
+                .into(),
+            "eseb1::deterministic_aes256_ecb::",
+        );
+        round_trip(
+            DeterministicAeadKey::gen_key().unwrap().into(),
+            "eseb1::aes_siv::",
+        );
+        round_trip(
+            WideBlockEncryptionKey256::gen_key().unwrap().into(),
+            "eseb1::wide_block_aes256::",
+        );
+        round_trip(
+            FormatPreservingKey::gen_key().unwrap().into(),
+            "eseb1::ff1_aes256::",
+        );
+        round_trip(
+            BlindIndexKey::gen_key().unwrap().into(),
+            "eseb1::blind_index::",
+        );
+        round_trip(OpaqueKey::new(b"hi".to_vec()).into(), "eseb0::opaque_key::");
+        round_trip(pair.to_public().into(), "eseb1::snow_public_key::");
+        round_trip(pair.to_private().into(), "eseb1::snow_private_key::");
+        round_trip(pair.to_psk().into(), "eseb1::snow_preshared_key::");
+        round_trip(
+            SnowPsk::gen_key().unwrap().into(),
+            "eseb1::snow_preshared_key::",
+        );
+        round_trip(pair.into(), "eseb1::snow_key_pair::");
+
+        let key: AnyKey = SymmetricKey::gen_key().unwrap().into();
+        for share in key.split(2, 2).unwrap() {
+            round_trip(share.into(), "eseb1::share::");
+        }
+
+        let e = AnyKey::from_str("eseb9::unknown::AAAA").err().unwrap();
+        assert!(matches!(
+            e.downcast_ref::<KeyParseError>(),
+            Some(KeyParseError::WrongHeader { .. })
+        ));
+    }
+
+    #[test]
+    fn test_bch_dispatch() {
+        let key: AnyKey = SnowKeyPair::gen_key().unwrap().into();
+        let ser = key.serialize_with(KeyEncoding::Bch);
+        assert!(ser.starts_with("eseb2::snow_key_pair::"));
+        assert_eq!(AnyKey::detect_header(&ser), Some(SnowKeyPair::HEADER));
+        let deser = AnyKey::from_str(&ser).unwrap();
+        assert_eq!(deser.key_bytes(), key.key_bytes());
+        assert_eq!(deser.serialize_to_string(), key.serialize_to_string());
+    }
+
+    #[test]
+    fn test_public_key() {
+        let pair = SnowKeyPair::gen_key().unwrap();
+        let public = pair.to_public().key_bytes();
+        let key: AnyKey = pair.into();
+        assert_eq!(key.public_key().unwrap().key_bytes(), public);
+        let key = AnyKey::SnowPublicKey(key.public_key().unwrap());
+        assert_eq!(key.public_key().unwrap().key_bytes(), public);
+        let key: AnyKey = SymmetricKey::gen_key().unwrap().into();
+        assert!(key.public_key().is_none());
+    }
+
+    #[test]
+    fn test_names() {
+        let key: AnyKey = SnowKeyPair::gen_key().unwrap().into();
+        assert_eq!(key.type_name(), "snow_key_pair");
+        assert_eq!(key.version(), "eseb1");
+
+        let key: AnyKey = SymmetricKey::gen_key().unwrap().into();
+        assert_eq!(key.type_name(), "sym");
+        assert_eq!(key.version(), "eseb0");
+    }
+
+    #[test]
+    fn test_try_from() {
+        let key: AnyKey = SymmetricKey::gen_key().unwrap().into();
+        assert!(SnowPsk::try_from(key.clone()).is_err());
+        assert_eq!(
+            SymmetricKey::try_from(key.clone()).unwrap().key_bytes(),
+            key.key_bytes()
+        );
+    }
+
+    #[test]
+    fn test_unknown_header() {
+        assert!(AnyKey::from_str("eseb1::nope::AAAA::12345").is_err());
+        assert!(AnyKey::detect_header("eseb1::nope::AAAA::12345").is_none());
+    }
+
+    #[test]
+    fn test_serde() {
+        let key: AnyKey = SnowKeyPair::gen_key().unwrap().into();
+        let ser_key = bincode::serialize(&key).unwrap();
+        let deser_key: AnyKey = bincode::deserialize(&ser_key).unwrap();
+        assert_eq!(deser_key.header(), key.header());
+        assert_eq!(deser_key.key_bytes(), key.key_bytes());
+
+        // Serializes exactly like the inner key.
+        let inner: SnowKeyPair = deser_key.try_into().unwrap();
+        assert_eq!(ser_key, bincode::serialize(&inner).unwrap());
+    }
+}
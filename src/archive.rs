@@ -0,0 +1,561 @@
+use std::fs::Metadata;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path};
+
+use anyhow::{Context, Result};
+use record_reader::{Format, IoRecordReader, IoRecordWriter, RecordReader, RecordWriter};
+use serde::{Deserialize, Serialize};
+
+use crate::{DecryptingRecordReader, EncryptingRecordWriter, StreamError, SymmetricKey};
+
+// An archive is this magic, a random archive ID, one encrypted stream per
+// member, an encrypted table of contents, and a footer: the offset of the
+// table of contents as a little endian u64, then the magic again. Streams are
+// in the `Record` format; each member's starts with its `ArchiveEntry`.
+const MAGIC: &[u8; 8] = b"ESEBARC1";
+const ID_LEN: usize = 16;
+const HEADER_LEN: u64 = (MAGIC.len() + ID_LEN) as u64;
+const FOOTER_LEN: u64 = 16;
+
+// Every archive has its own key, a keyed hash of its ID, so that streams from
+// different archives under the same key cannot be mixed. Member `i` is
+// encrypted under subkey `i` of that, so members cannot be swapped around,
+// and no member can pass for the table of contents.
+const ARCHIVE_CONTEXT: &[u8] = b"esebarch";
+const MEMBER_CONTEXT: &str = "esebamem";
+const TOC_CONTEXT: &str = "esebatoc";
+
+const CHUNK_LEN: usize = 1 << 16;
+
+/// A file in an archive.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchiveEntry {
+    /// Relative, `/` separated, with no `.` or `..` components.
+    pub path: String,
+    /// Unix permission bits.
+    pub mode: u32,
+    /// Modification time, in seconds since the Unix epoch.
+    pub mtime: i64,
+    pub size: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TocEntry {
+    #[serde(flatten)]
+    entry: ArchiveEntry,
+    compressed: bool,
+    // Where the member's stream is in the archive.
+    offset: u64,
+    length: u64,
+}
+
+/// Writes an archive of encrypted files. Every member is its own stream, so
+/// that `ArchiveReader` can list the archive and extract single members
+/// without decrypting the rest.
+pub struct ArchiveWriter<W: Write> {
+    inner: CountingWriter<W>,
+    key: SymmetricKey,
+    compress: bool,
+    toc: Vec<TocEntry>,
+    // Files `add_path` must not archive.
+    skip: Vec<FileId>,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(writer: W, key: &SymmetricKey, compress: bool) -> Result<ArchiveWriter<W>> {
+        let mut inner = CountingWriter {
+            inner: writer,
+            count: 0,
+        };
+        let id = sodiumoxide::randombytes::randombytes(ID_LEN);
+        inner
+            .write_all(MAGIC)
+            .and_then(|()| inner.write_all(&id))
+            .context("write archive header")?;
+        Ok(ArchiveWriter {
+            inner,
+            key: key.derive_keyed(ARCHIVE_CONTEXT, &id)?,
+            compress,
+            toc: Vec::default(),
+            skip: Vec::default(),
+        })
+    }
+
+    /// Makes `add_path` leave out the file at `path`, such as the archive
+    /// itself when it is written inside a directory being archived. Does
+    /// nothing if there is no such file.
+    pub fn skip(&mut self, path: &Path) -> Result<()> {
+        match std::fs::metadata(path) {
+            Ok(metadata) => self.skip.push(file_id(path, &metadata)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("stat {}", path.display())),
+        }
+        Ok(())
+    }
+
+    /// Adds the file at `path`, or everything under it in name order if it is
+    /// a directory, under its path as given. Symlinks and other special files
+    /// are skipped with a warning.
+    pub fn add_path(&mut self, path: &Path) -> Result<()> {
+        let name = path
+            .to_str()
+            .with_context(|| format!("{} is not UTF-8", path.display()))?;
+        self.add_tree(path, name.strip_prefix("./").unwrap_or(name))
+    }
+
+    // Adds the file at `path` as `name`, or what is under it as `name/...`.
+    fn add_tree(&mut self, path: &Path, name: &str) -> Result<()> {
+        let metadata =
+            std::fs::symlink_metadata(path).with_context(|| format!("stat {}", path.display()))?;
+        if metadata.is_dir() {
+            let mut children = std::fs::read_dir(path)
+                .and_then(|dir| {
+                    dir.map(|child| child.map(|child| child.file_name()))
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .with_context(|| format!("read directory {}", path.display()))?;
+            children.sort();
+            for child in children {
+                let child_name = child
+                    .to_str()
+                    .with_context(|| format!("{} is not UTF-8", path.join(&child).display()))?;
+                let child_name = if name == "." {
+                    child_name.to_string()
+                } else {
+                    format!("{}/{}", name.trim_end_matches('/'), child_name)
+                };
+                self.add_tree(&path.join(&child), &child_name)?;
+            }
+        } else if metadata.is_file() {
+            if self.skip.contains(&file_id(path, &metadata)?) {
+                return Ok(());
+            }
+            #[cfg(unix)]
+            let mode = std::os::unix::fs::PermissionsExt::mode(&metadata.permissions()) & 0o7777;
+            #[cfg(not(unix))]
+            let mode = 0o644;
+            let mtime = metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0, |since| since.as_secs() as i64);
+            let entry = ArchiveEntry {
+                path: name.to_string(),
+                mode,
+                mtime,
+                size: metadata.len(),
+            };
+            let file =
+                std::fs::File::open(path).with_context(|| format!("open {}", path.display()))?;
+            self.add(entry, file)?;
+        } else {
+            eprintln!("{}: not a regular file, skipped", path.display());
+        }
+        Ok(())
+    }
+
+    /// Adds a member with the contents of `reader`, which must be exactly
+    /// `entry.size` bytes. After an error, the archive is only good for
+    /// throwing away.
+    pub fn add<R: Read>(&mut self, entry: ArchiveEntry, mut reader: R) -> Result<()> {
+        check_path(&entry.path)?;
+        if self.toc.iter().any(|toc| toc.entry.path == entry.path) {
+            anyhow::bail!("{} is already in the archive", entry.path);
+        }
+        let key = self.key.derive(MEMBER_CONTEXT, self.toc.len() as u64)?;
+        let offset = self.inner.count;
+        let records = IoRecordWriter::new(&mut self.inner, Format::Record);
+        let mut encrypter = EncryptingRecordWriter::new(records, key, self.compress)?;
+        encrypter
+            .write_record(&serde_json::to_vec(&entry)?)
+            .context("write member metadata")?;
+        let mut buf = vec![0; CHUNK_LEN];
+        let mut size = 0;
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e).with_context(|| format!("read {}", entry.path)),
+            };
+            encrypter.write_record(&buf[..n])?;
+            size += n as u64;
+        }
+        encrypter.into_inner()?;
+        if size != entry.size {
+            anyhow::bail!(
+                "{} is {} bytes, not the {} expected; did it change?",
+                entry.path,
+                size,
+                entry.size
+            );
+        }
+        self.toc.push(TocEntry {
+            entry,
+            compressed: self.compress,
+            offset,
+            length: self.inner.count - offset,
+        });
+        Ok(())
+    }
+
+    /// Writes the table of contents and returns the writer.
+    pub fn finish(mut self) -> Result<W> {
+        let toc_offset = self.inner.count;
+        let key = self.key.derive(TOC_CONTEXT, 0)?;
+        let records = IoRecordWriter::new(&mut self.inner, Format::Record);
+        let mut encrypter = EncryptingRecordWriter::new(records, key, false)?;
+        encrypter
+            .write_record(&serde_json::to_vec(&self.toc)?)
+            .context("write table of contents")?;
+        encrypter.into_inner()?;
+        self.inner.write_all(&toc_offset.to_le_bytes())?;
+        self.inner.write_all(MAGIC)?;
+        self.inner.flush()?;
+        Ok(self.inner.inner)
+    }
+}
+
+/// Reads an archive made by `ArchiveWriter`. Opening it decrypts only the
+/// table of contents.
+pub struct ArchiveReader<R: Read + Seek> {
+    inner: R,
+    key: SymmetricKey,
+    toc: Vec<TocEntry>,
+    entries: Vec<ArchiveEntry>,
+}
+
+impl<R: Read + Seek> ArchiveReader<R> {
+    pub fn new(mut reader: R, key: &SymmetricKey) -> Result<ArchiveReader<R>> {
+        let mut magic = [0; MAGIC.len()];
+        reader
+            .read_exact(&mut magic)
+            .ok()
+            .filter(|()| &magic == MAGIC)
+            .context("not an eseb archive")?;
+        let mut id = [0; ID_LEN];
+        reader
+            .read_exact(&mut id)
+            .map_err(|_| StreamError::Truncated)
+            .context("archive has no ID")?;
+        let end = reader.seek(SeekFrom::End(0))?;
+        if end < HEADER_LEN + FOOTER_LEN {
+            return Err(StreamError::Truncated).context("archive has no footer");
+        }
+        let mut footer = [0; FOOTER_LEN as usize];
+        reader.seek(SeekFrom::Start(end - FOOTER_LEN))?;
+        reader.read_exact(&mut footer)?;
+        let (toc_offset, magic) = footer.split_at(8);
+        if magic != MAGIC {
+            return Err(StreamError::Truncated).context("archive has no footer");
+        }
+        let toc_offset = u64::from_le_bytes(toc_offset.try_into().expect("8 bytes"));
+        let toc_end = end - FOOTER_LEN;
+        if toc_offset < HEADER_LEN || toc_offset > toc_end {
+            anyhow::bail!("bad table of contents offset {}", toc_offset);
+        }
+
+        let key = key.derive_keyed(ARCHIVE_CONTEXT, &id)?;
+        let mut toc: Vec<TocEntry> = Vec::default();
+        read_stream(
+            &mut reader,
+            key.derive(TOC_CONTEXT, 0)?,
+            false,
+            toc_offset,
+            toc_end - toc_offset,
+            |i, record| {
+                if i > 0 {
+                    anyhow::bail!("unexpected record after the table of contents");
+                }
+                toc = serde_json::from_slice(record)?;
+                Ok(())
+            },
+        )
+        .context("read table of contents")?;
+        for entry in &toc {
+            check_path(&entry.entry.path)?;
+            if entry.offset < HEADER_LEN || entry.offset.saturating_add(entry.length) > toc_offset {
+                anyhow::bail!("{}: bad offset", entry.entry.path);
+            }
+        }
+        let entries = toc.iter().map(|toc| toc.entry.clone()).collect();
+        Ok(ArchiveReader {
+            inner: reader,
+            key,
+            toc,
+            entries,
+        })
+    }
+
+    /// The members, in the order they were added.
+    pub fn entries(&self) -> &[ArchiveEntry] {
+        &self.entries
+    }
+
+    /// Decrypts member number `index` of `entries` into `writer`. Output is
+    /// written as it is verified, so on error the caller must discard it;
+    /// `AtomicFile` does this.
+    pub fn extract<W: Write>(&mut self, index: usize, mut writer: W) -> Result<()> {
+        let toc = self.toc.get(index).context("no such member")?;
+        let mut size = 0;
+        read_stream(
+            &mut self.inner,
+            self.key.derive(MEMBER_CONTEXT, index as u64)?,
+            toc.compressed,
+            toc.offset,
+            toc.length,
+            |i, record| {
+                if i == 0 {
+                    let entry: ArchiveEntry = serde_json::from_slice(record)?;
+                    if entry != toc.entry {
+                        anyhow::bail!("metadata does not match the table of contents");
+                    }
+                } else {
+                    writer.write_all(record)?;
+                    size += record.len() as u64;
+                }
+                Ok(())
+            },
+        )
+        .with_context(|| format!("extract {}", toc.entry.path))?;
+        if size != toc.entry.size {
+            anyhow::bail!("{}: wrong size {}", toc.entry.path, size);
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+// Decrypts the stream of `length` bytes at `offset`, calling `f` with the
+// index and cleartext of each record. The stream must be complete and fill
+// the whole range.
+fn read_stream<R, F>(
+    reader: &mut R,
+    key: SymmetricKey,
+    compress: bool,
+    offset: u64,
+    length: u64,
+    mut f: F,
+) -> Result<()>
+where
+    R: Read + Seek,
+    F: FnMut(usize, &[u8]) -> Result<()>,
+{
+    reader.seek(SeekFrom::Start(offset))?;
+    // No record can be longer than the range, whatever its length prefix says.
+    let max_record = usize::try_from(length).unwrap_or(usize::MAX);
+    let records = IoRecordReader::from_read(reader.take(length), Format::Record, max_record);
+    let mut decrypter = DecryptingRecordReader::new(records, key, compress)?;
+    let mut i = 0;
+    while let Some(record) = decrypter
+        .maybe_read_record()
+        .context("decrypt and verify record")?
+    {
+        f(i, record)?;
+        i += 1;
+    }
+    if i == 0 {
+        return Err(StreamError::Truncated.into());
+    }
+    if decrypter
+        .into_inner()
+        .maybe_read_record()
+        .context("read record")?
+        .is_some()
+    {
+        return Err(StreamError::TrailingData.into());
+    }
+    Ok(())
+}
+
+// Extracting must never write outside the target directory.
+fn check_path(path: &str) -> Result<()> {
+    let normal: Option<Vec<_>> = Path::new(path)
+        .components()
+        .map(|c| match c {
+            Component::Normal(name) => name.to_str(),
+            _ => None,
+        })
+        .collect();
+    if path.is_empty()
+        || path.contains('\\')
+        || normal.map(|names| names.join("/")).as_deref() != Some(path)
+    {
+        anyhow::bail!("unsafe path in archive: {:?}", path);
+    }
+    Ok(())
+}
+
+// Identifies a file whatever path it is reached by.
+#[cfg(unix)]
+type FileId = (u64, u64);
+#[cfg(not(unix))]
+type FileId = std::path::PathBuf;
+
+#[cfg(unix)]
+fn file_id(_path: &Path, metadata: &Metadata) -> Result<FileId> {
+    use std::os::unix::fs::MetadataExt;
+    Ok((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(path: &Path, _metadata: &Metadata) -> Result<FileId> {
+    std::fs::canonicalize(path).with_context(|| format!("resolve {}", path.display()))
+}
+
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    fn entry(path: &str, size: u64) -> ArchiveEntry {
+        ArchiveEntry {
+            path: path.to_string(),
+            mode: 0o640,
+            mtime: 1_700_000_000,
+            size,
+        }
+    }
+
+    fn archive(key: &SymmetricKey, compress: bool) -> Vec<u8> {
+        let mut writer = ArchiveWriter::new(Vec::default(), key, compress).unwrap();
+        writer.add(entry("a.txt", 5), &b"hello"[..]).unwrap();
+        writer.add(entry("dir/empty", 0), &b""[..]).unwrap();
+        let big = vec![7; 3 * CHUNK_LEN + 1];
+        writer
+            .add(entry("dir/big", big.len() as u64), &big[..])
+            .unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn extract(reader: &mut ArchiveReader<Cursor<Vec<u8>>>, index: usize) -> Result<Vec<u8>> {
+        let mut v = Vec::default();
+        reader.extract(index, &mut v)?;
+        Ok(v)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let key = SymmetricKey::gen_key().unwrap();
+        for compress in [false, true] {
+            let data = archive(&key, compress);
+            let mut reader = ArchiveReader::new(Cursor::new(data), &key).unwrap();
+            let paths: Vec<_> = reader.entries().iter().map(|e| e.path.clone()).collect();
+            assert_eq!(paths, ["a.txt", "dir/empty", "dir/big"]);
+            assert_eq!(reader.entries()[0], entry("a.txt", 5));
+            // Any member, in any order.
+            assert_eq!(extract(&mut reader, 2).unwrap(), vec![7; 3 * CHUNK_LEN + 1]);
+            assert_eq!(extract(&mut reader, 0).unwrap(), b"hello");
+            assert_eq!(extract(&mut reader, 1).unwrap(), b"");
+            assert!(extract(&mut reader, 3).is_err());
+        }
+        let empty = ArchiveWriter::new(Vec::default(), &key, false)
+            .unwrap()
+            .finish()
+            .unwrap();
+        let reader = ArchiveReader::new(Cursor::new(empty), &key).unwrap();
+        assert!(reader.entries().is_empty());
+    }
+
+    #[test]
+    fn test_add_path_skips_output() {
+        let key = SymmetricKey::gen_key().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        std::fs::create_dir(&src).unwrap();
+        std::fs::write(src.join("a.txt"), b"hello").unwrap();
+        // Left over from an earlier run.
+        let path = src.join("out.eseba");
+        std::fs::write(&path, b"old archive").unwrap();
+
+        let output = crate::AtomicFile::new(&path).unwrap();
+        let temp_path = output.temp_path().to_path_buf();
+        let mut writer = ArchiveWriter::new(output, &key, false).unwrap();
+        writer.skip(&temp_path).unwrap();
+        writer.skip(&path).unwrap();
+        writer.add_tree(&src, "src").unwrap();
+        writer.finish().unwrap().commit().unwrap();
+
+        let reader = ArchiveReader::new(std::fs::File::open(&path).unwrap(), &key).unwrap();
+        let paths: Vec<_> = reader.entries().iter().map(|e| e.path.clone()).collect();
+        assert_eq!(paths, ["src/a.txt"]);
+    }
+
+    #[test]
+    fn test_tampering() {
+        let key = SymmetricKey::gen_key().unwrap();
+        let data = archive(&key, false);
+
+        let other = SymmetricKey::gen_key().unwrap();
+        assert!(ArchiveReader::new(Cursor::new(data.clone()), &other).is_err());
+        assert!(ArchiveReader::new(Cursor::new(data[..data.len() - 1].to_vec()), &key).is_err());
+        assert!(ArchiveReader::new(Cursor::new(b"ESEBARC1".to_vec()), &key).is_err());
+
+        // A corrupt member only affects that member.
+        let mut corrupt = data.clone();
+        corrupt[HEADER_LEN as usize + 40] ^= 1;
+        let mut reader = ArchiveReader::new(Cursor::new(corrupt), &key).unwrap();
+        assert!(extract(&mut reader, 0).is_err());
+        assert_eq!(extract(&mut reader, 1).unwrap(), b"");
+
+        // Members encrypted in one place do not decrypt in another.
+        let mut writer = ArchiveWriter::new(Vec::default(), &key, false).unwrap();
+        writer.add(entry("a.txt", 5), &b"hello"[..]).unwrap();
+        writer.add(entry("b.txt", 5), &b"world"[..]).unwrap();
+        let data = writer.finish().unwrap();
+        let mut reader = ArchiveReader::new(Cursor::new(data), &key).unwrap();
+        reader.toc.swap(0, 1);
+        assert!(extract(&mut reader, 0).is_err());
+        assert!(extract(&mut reader, 1).is_err());
+
+        // Nor do members or tables of contents of another archive.
+        let a = archive(&key, false);
+        let b = archive(&key, false);
+        assert_eq!(a.len(), b.len());
+        let mut spliced = a[..HEADER_LEN as usize].to_vec();
+        spliced.extend_from_slice(&b[HEADER_LEN as usize..]);
+        assert!(ArchiveReader::new(Cursor::new(spliced), &key).is_err());
+        let mut reader = ArchiveReader::new(Cursor::new(a), &key).unwrap();
+        reader.inner = Cursor::new(b);
+        assert!(extract(&mut reader, 0).is_err());
+    }
+
+    #[test]
+    fn test_bad_entries() {
+        let key = SymmetricKey::gen_key().unwrap();
+        let mut writer = ArchiveWriter::new(Vec::default(), &key, false).unwrap();
+        for path in [
+            "",
+            "/etc/passwd",
+            "../x",
+            "a/../../x",
+            "./a",
+            "a//b",
+            "a/",
+            "a\\b",
+        ] {
+            assert!(writer.add(entry(path, 0), &b""[..]).is_err(), "{}", path);
+        }
+        writer.add(entry("a", 1), &b"x"[..]).unwrap();
+        assert!(writer.add(entry("a", 1), &b"x"[..]).is_err());
+        assert!(writer.add(entry("b", 2), &b"x"[..]).is_err());
+    }
+}
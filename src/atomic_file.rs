@@ -0,0 +1,209 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+/// A file that appears at `path` only once `commit` succeeds.
+///
+/// Data goes to a temporary file in the same directory, readable only by the
+/// owner. `commit` fsyncs it and renames it over `path`; dropping an
+/// `AtomicFile` without committing deletes the temporary file, so an error
+/// part way through never leaves partial output behind.
+///
+/// The result stays readable only by the owner, even if the file it
+/// replaces was not, unless it was made with `new_keep_mode`.
+pub struct AtomicFile {
+    path: PathBuf,
+    temp: tempfile::NamedTempFile,
+    keep_mode: bool,
+}
+
+impl AtomicFile {
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<AtomicFile> {
+        Self::create(path, /*keep_mode=*/ false)
+    }
+
+    /// Like `new`, but `commit` gives the result the mode of the file it
+    /// replaces, if there is one. Only for output that is no more secret
+    /// than what it replaces, such as re-encrypted ciphertext.
+    pub fn new_keep_mode<P: AsRef<Path>>(path: P) -> Result<AtomicFile> {
+        Self::create(path, /*keep_mode=*/ true)
+    }
+
+    fn create<P: AsRef<Path>>(path: P, keep_mode: bool) -> Result<AtomicFile> {
+        let path = path.as_ref().to_path_buf();
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut builder = tempfile::Builder::new();
+        builder.prefix(".eseb").suffix(".tmp");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            builder.permissions(std::fs::Permissions::from_mode(0o600));
+        }
+        let temp = builder
+            .tempfile_in(dir)
+            .with_context(|| format!("create temporary file in {}", dir.display()))?;
+        Ok(AtomicFile {
+            path,
+            temp,
+            keep_mode,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Where the data is until `commit`.
+    pub fn temp_path(&self) -> &Path {
+        self.temp.path()
+    }
+
+    pub fn commit(self) -> Result<()> {
+        self.finish(/*replace=*/ true)
+    }
+
+    /// Like `commit`, but fails if `path` already exists, rather than
+    /// replacing it.
+    pub fn commit_new(self) -> Result<()> {
+        self.finish(/*replace=*/ false)
+    }
+
+    fn finish(mut self, replace: bool) -> Result<()> {
+        self.temp.flush().context("flush temporary file")?;
+        match std::fs::metadata(&self.path) {
+            Ok(_) if !replace => anyhow::bail!("{} already exists", self.path.display()),
+            Ok(_) if !self.keep_mode => {}
+            Ok(metadata) => self
+                .temp
+                .as_file()
+                .set_permissions(metadata.permissions())
+                .context("copy permissions to temporary file")?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("stat {}", self.path.display())),
+        }
+        self.temp
+            .as_file()
+            .sync_all()
+            .context("fsync temporary file")?;
+        let persisted = if replace {
+            self.temp.persist(&self.path)
+        } else {
+            // Checked above, but something may have appeared since.
+            self.temp.persist_noclobber(&self.path)
+        };
+        persisted
+            .map_err(|e| e.error)
+            .with_context(|| format!("rename temporary file to {}", self.path.display()))?;
+
+        // Make the rename itself durable.
+        #[cfg(unix)]
+        if let Some(dir) = self.path.parent() {
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            std::fs::File::open(dir)
+                .and_then(|dir| dir.sync_all())
+                .with_context(|| format!("fsync {}", dir.display()))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.temp.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.temp.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        std::fs::write(&path, b"old").unwrap();
+
+        let mut file = AtomicFile::new(&path).unwrap();
+        file.write_all(b"new").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"old");
+        file.commit().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        // New files are readable only by the owner.
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let path = dir.path().join("new");
+            AtomicFile::new(&path).unwrap().commit().unwrap();
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let mode = || std::fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+        std::fs::write(&path, b"old").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+
+        let mut file = AtomicFile::new_keep_mode(&path).unwrap();
+        file.write_all(b"new").unwrap();
+        file.commit().unwrap();
+        assert_eq!(mode(), 0o640);
+
+        // Plaintext written over a readable file, as decrypt -o does, is not
+        // readable by anyone else.
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let mut file = AtomicFile::new(&path).unwrap();
+        file.write_all(b"cleartext").unwrap();
+        file.commit().unwrap();
+        assert_eq!(mode(), 0o600);
+    }
+
+    #[test]
+    fn test_commit_new() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+
+        let mut file = AtomicFile::new(&path).unwrap();
+        file.write_all(b"first").unwrap();
+        file.commit_new().unwrap();
+
+        let mut file = AtomicFile::new(&path).unwrap();
+        file.write_all(b"second").unwrap();
+        assert!(file.commit_new().is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"first");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_drop_without_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+
+        let mut file = AtomicFile::new(&path).unwrap();
+        file.write_all(b"partial").unwrap();
+        drop(file);
+        assert!(!path.exists());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
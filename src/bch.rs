@@ -0,0 +1,301 @@
+//! The BCH checksum from bech32m (BIP 350), used for the `eseb2` key
+//! encoding. The payload is split into groups, each followed by its own
+//! checksum, so every group stays short enough that any error affecting up to
+//! 4 of its characters is guaranteed to be detected.
+
+use crate::key_util::KeyParseError;
+
+const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+const BECH32M_CONST: u32 = 0x2bc830a3;
+const CHECKSUM_LEN: usize = 6;
+
+// The detection guarantee holds for up to 89 symbols of data plus checksum.
+const GROUP_LEN: usize = 80;
+
+pub(crate) const SEPARATOR: char = '-';
+
+fn polymod(values: &[u8]) -> u32 {
+    let mut chk = 1u32;
+    for value in values {
+        let top = chk >> 25;
+        chk = (chk & 0x1ffffff) << 5 ^ *value as u32;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= generator;
+            }
+        }
+    }
+    chk
+}
+
+// Binds each group to the key header and its position, so groups cannot be
+// reordered, dropped or moved to another key type.
+fn prefix(header: &str, index: usize, count: usize) -> Vec<u8> {
+    let mut v: Vec<u8> = header.bytes().map(|c| c >> 5).collect();
+    v.push(0);
+    v.extend(header.bytes().map(|c| c & 31));
+    for n in [index, count] {
+        v.push((n >> 5 & 31) as u8);
+        v.push((n & 31) as u8);
+    }
+    v
+}
+
+fn is_valid(prefix: &[u8], symbols: &[u8]) -> bool {
+    let mut values = prefix.to_vec();
+    values.extend_from_slice(symbols);
+    polymod(&values) == BECH32M_CONST
+}
+
+fn checksum(prefix: &[u8], data: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let mut values = prefix.to_vec();
+    values.extend_from_slice(data);
+    values.extend_from_slice(&[0; CHECKSUM_LEN]);
+    let chk = polymod(&values) ^ BECH32M_CONST;
+    let mut v = [0; CHECKSUM_LEN];
+    for (i, symbol) in v.iter_mut().enumerate() {
+        *symbol = (chk >> (5 * (CHECKSUM_LEN - 1 - i)) & 31) as u8;
+    }
+    v
+}
+
+fn to_symbols(data: &[u8]) -> Vec<u8> {
+    let mut v = Vec::with_capacity((data.len() * 8).div_ceil(5));
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for byte in data {
+        acc = acc << 8 | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            v.push((acc >> bits & 31) as u8);
+        }
+    }
+    if bits > 0 {
+        v.push((acc << (5 - bits) & 31) as u8);
+    }
+    v
+}
+
+fn from_symbols(symbols: &[u8]) -> Result<Vec<u8>, KeyParseError> {
+    let mut v = Vec::with_capacity(symbols.len() * 5 / 8);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for symbol in symbols {
+        acc = acc << 5 | *symbol as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            v.push((acc >> bits & 255) as u8);
+        }
+    }
+    if bits >= 5 || acc & ((1 << bits) - 1) != 0 {
+        return Err(KeyParseError::InvalidPayload(
+            "bad padding in key data".to_string(),
+        ));
+    }
+    Ok(v)
+}
+
+/// Encodes `data` as checksummed groups joined by `SEPARATOR`.
+pub(crate) fn encode(header: &str, data: &[u8]) -> String {
+    let symbols = to_symbols(data);
+    let count = std::cmp::max(1, symbols.len().div_ceil(GROUP_LEN));
+    let mut v = String::default();
+    for index in 0..count {
+        if index > 0 {
+            v.push(SEPARATOR);
+        }
+        let end = std::cmp::min(symbols.len(), (index + 1) * GROUP_LEN);
+        let group = &symbols[index * GROUP_LEN..end];
+        let checksum = checksum(&prefix(header, index, count), group);
+        for symbol in group.iter().chain(checksum.iter()) {
+            v.push(CHARSET[*symbol as usize] as char);
+        }
+    }
+    v
+}
+
+/// Decodes the output of `encode`. `offset` is where `groups` starts in the
+/// full key string, so that error positions refer to the string the user
+/// typed.
+pub(crate) fn decode(header: &str, groups: &str, offset: usize) -> Result<Vec<u8>, KeyParseError> {
+    let count = groups.split(SEPARATOR).count();
+    let mut data = Vec::default();
+    let mut start = offset;
+    for (index, group) in groups.split(SEPARATOR).enumerate() {
+        let mut symbols = Vec::with_capacity(group.len());
+        for (i, c) in group.char_indices() {
+            let symbol = CHARSET
+                .iter()
+                .position(|x| *x as char == c.to_ascii_lowercase())
+                .ok_or(KeyParseError::InvalidCharacter {
+                    position: start + i,
+                })?;
+            symbols.push(symbol as u8);
+        }
+
+        if symbols.len() < CHECKSUM_LEN {
+            return Err(KeyParseError::BadChecksum {
+                positions: Vec::default(),
+            });
+        }
+
+        let prefix = prefix(header, index, count);
+        if !is_valid(&prefix, &symbols) {
+            return Err(KeyParseError::BadChecksum {
+                positions: locate_errors(&prefix, &symbols)
+                    .into_iter()
+                    .map(|i| start + i)
+                    .collect(),
+            });
+        }
+
+        data.extend_from_slice(&symbols[..symbols.len() - CHECKSUM_LEN]);
+        start += group.len() + SEPARATOR.len_utf8();
+    }
+
+    from_symbols(&data)
+}
+
+// Positions where a single substituted character, or two swapped adjacent
+// characters, would make the checksum valid. Empty if the damage is worse than
+// that; the checksum still detects it, but we cannot say where it is.
+fn locate_errors(prefix: &[u8], symbols: &[u8]) -> Vec<usize> {
+    let mut positions = Vec::default();
+    let mut candidate = symbols.to_vec();
+    for i in 0..symbols.len() {
+        for value in 0..32 {
+            if value == symbols[i] {
+                continue;
+            }
+            candidate[i] = value;
+            if is_valid(prefix, &candidate) {
+                positions.push(i);
+            }
+        }
+        candidate[i] = symbols[i];
+    }
+
+    for i in 1..symbols.len() {
+        if symbols[i] == symbols[i - 1] {
+            continue;
+        }
+        candidate.swap(i - 1, i);
+        if is_valid(prefix, &candidate) {
+            positions.push(i - 1);
+            positions.push(i);
+        }
+        candidate.swap(i - 1, i);
+    }
+
+    positions.sort_unstable();
+    positions.dedup();
+    positions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+
+    const HEADER: &str = "eseb2::sym::";
+
+    fn corrupt(encoded: &str, position: usize, delta: usize) -> String {
+        let mut v = encoded.as_bytes().to_vec();
+        let symbol = CHARSET.iter().position(|x| *x == v[position]).unwrap();
+        v[position] = CHARSET[(symbol + delta) % 32];
+        String::from_utf8(v).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for len in [0, 1, 31, 32, 48, 49, 50, 96, 200] {
+            let data: Vec<u8> = (0..len).map(|i| (i * 7 + 3) as u8).collect();
+            let encoded = encode(HEADER, &data);
+            assert_eq!(decode(HEADER, &encoded, 0).unwrap(), data);
+            assert_eq!(
+                decode(HEADER, &encoded.to_ascii_uppercase(), 0).unwrap(),
+                data
+            );
+        }
+    }
+
+    #[test]
+    fn test_groups() {
+        let encoded = encode(HEADER, &[1; 96]);
+        let groups: Vec<&str> = encoded.split(SEPARATOR).collect();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].len(), GROUP_LEN + CHECKSUM_LEN);
+
+        let swapped = format!("{}{}{}", groups[1], SEPARATOR, groups[0]);
+        assert!(decode(HEADER, &swapped, 0).is_err());
+        assert!(decode(HEADER, groups[0], 0).is_err());
+        assert!(decode("eseb2::snow_key_pair::", &encoded, 0).is_err());
+    }
+
+    #[test]
+    fn test_locate_substitution() {
+        let encoded = encode(HEADER, &[42; 32]);
+        for position in 0..encoded.len() {
+            let bad = corrupt(&encoded, position, 1);
+            match decode(HEADER, &bad, 100) {
+                Err(KeyParseError::BadChecksum { positions }) => {
+                    assert_eq!(positions, vec![position + 100])
+                }
+                other => panic!("{:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_locate_transposition() {
+        let encoded = encode(HEADER, b"a transposition test key string!");
+        let mut bad = encoded.as_bytes().to_vec();
+        let position = (1..bad.len()).find(|i| bad[*i] != bad[i - 1]).unwrap();
+        bad.swap(position - 1, position);
+        match decode(HEADER, std::str::from_utf8(&bad).unwrap(), 0) {
+            Err(KeyParseError::BadChecksum { positions }) => {
+                assert!(positions.contains(&(position - 1)));
+                assert!(positions.contains(&position));
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_invalid_character() {
+        let encoded = encode(HEADER, &[0; 32]);
+        let bad = format!("{}b{}", &encoded[..5], &encoded[6..]);
+        assert_eq!(
+            decode(HEADER, &bad, 12),
+            Err(KeyParseError::InvalidCharacter { position: 17 })
+        );
+        let bad = format!("{}\u{e9}{}", &encoded[..5], &encoded[6..]);
+        assert_eq!(
+            decode(HEADER, &bad, 0),
+            Err(KeyParseError::InvalidCharacter { position: 5 })
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn test_detects_up_to_four_errors(
+            data in prop::collection::vec(any::<u8>(), 0..100),
+            errors in prop::collection::vec((any::<prop::sample::Index>(), 1..32usize), 1..=4),
+        ) {
+            let encoded = encode(HEADER, &data);
+            let group_len = std::cmp::min(encoded.len(), GROUP_LEN + CHECKSUM_LEN);
+            let mut bad = encoded.clone();
+            // All errors in the first group; errors spread over several
+            // groups leave fewer in each.
+            for (position, delta) in errors {
+                bad = corrupt(&bad, position.index(group_len), delta);
+            }
+            prop_assume!(bad != encoded);
+            prop_assert!(decode(HEADER, &bad, 0).is_err());
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use anyhow::Result;
+use sodiumoxide::crypto::generichash;
+
+use crate::key_util::*;
+
+const KEY_LEN: usize = 32;
+const DIGEST_LEN: usize = 32;
+
+/// Computes blind indexes: short keyed BLAKE2b tags of field values, stored
+/// next to the encrypted values so that equality searches need no decryption.
+/// Unlike `DeterministicEncryptionSymmetricKey256`, the key cannot recover
+/// any value, so it is safe to hand to the services that only search.
+///
+/// Tags depend on the index name as well as the value, so equal values in
+/// different columns do not match. Shorter tags give more false positives,
+/// which a search must filter out after decrypting, but also tell an attacker
+/// less about which values are equal; pick the length per index and keep it.
+#[derive(Clone)]
+pub struct BlindIndexKey {
+    key: [u8; KEY_LEN],
+}
+
+crate::serde_support::derive_serde!(BlindIndexKey, BlindIndexKeyVisitor);
+
+impl KeyMaterial for BlindIndexKey {
+    const HEADER: &'static str = "eseb1::blind_index::";
+    fn key_bytes(&self) -> Vec<u8> {
+        self.key.to_vec()
+    }
+}
+
+impl DerivableKey for BlindIndexKey {
+    const KDF_CONTEXT: &'static str = "esebbidx";
+    const DERIVED_LEN: usize = KEY_LEN;
+    fn from_derived(data: &[u8]) -> Result<BlindIndexKey> {
+        Self::from_slice(data)
+    }
+}
+
+impl std::str::FromStr for BlindIndexKey {
+    type Err = anyhow::Error;
+    fn from_str(data: &str) -> Result<BlindIndexKey> {
+        let key_data = parse_header(data.trim(), &Self::HEADER)?;
+        Self::from_slice(&key_data)
+    }
+}
+
+impl BlindIndexKey {
+    pub fn gen_key() -> Result<BlindIndexKey> {
+        Self::from_slice(&sodiumoxide::randombytes::randombytes(KEY_LEN))
+    }
+
+    /// The first `tag_len` bytes (1 to 32) of the tag of `value` in the index
+    /// `name`, e.g. a column name.
+    pub fn index(&self, name: &str, value: &[u8], tag_len: usize) -> Result<Vec<u8>> {
+        self.compound_index(name, &[value], tag_len)
+    }
+
+    /// Like `index`, for an index over several fields at once, e.g. name and
+    /// date of birth. The values are length prefixed, so `["ab", "c"]` and
+    /// `["a", "bc"]` differ.
+    pub fn compound_index(&self, name: &str, values: &[&[u8]], tag_len: usize) -> Result<Vec<u8>> {
+        if tag_len == 0 || tag_len > DIGEST_LEN {
+            anyhow::bail!("tag length must be between 1 and {} bytes", DIGEST_LEN);
+        }
+        let mut state = generichash::State::new(Some(DIGEST_LEN), Some(&self.key))
+            .expect("valid blake2b parameters");
+        for part in std::iter::once(name.as_bytes()).chain(values.iter().copied()) {
+            state
+                .update(&(part.len() as u64).to_le_bytes())
+                .expect("hash index");
+            state.update(part).expect("hash index");
+        }
+        let digest = state.finalize().expect("finalize blake2b");
+        Ok(digest.as_ref()[..tag_len].to_vec())
+    }
+
+    fn from_slice(slice: &[u8]) -> Result<BlindIndexKey> {
+        check_length(slice, KEY_LEN)?;
+        Ok(BlindIndexKey {
+            key: slice.try_into().expect("checked length"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    // Checked against Python's hashlib.blake2b.
+    #[test]
+    fn test_known_answers() {
+        let key = BlindIndexKey::from_slice(&(0..32).collect::<Vec<u8>>()).unwrap();
+        assert_eq!(
+            hex::encode(key.index("email", b"alice@example.com", 16).unwrap()),
+            "188a97c862d59c91c68f374ffab17c6c"
+        );
+        assert_eq!(
+            hex::encode(
+                key.compound_index("name_dob", &[b"alice", b"1990-01-01"], 8)
+                    .unwrap()
+            ),
+            "dfe0f4fc93f8edfa"
+        );
+    }
+
+    #[test]
+    fn test_separation() {
+        let key = BlindIndexKey::gen_key().unwrap();
+        let tag = key.index("email", b"alice@example.com", 32).unwrap();
+        assert_eq!(tag, key.index("email", b"alice@example.com", 32).unwrap());
+        assert_eq!(
+            tag[..4],
+            key.index("email", b"alice@example.com", 4).unwrap()
+        );
+        assert_ne!(tag, key.index("login", b"alice@example.com", 32).unwrap());
+        assert_ne!(tag, key.index("email", b"bob@example.com", 32).unwrap());
+        assert_ne!(
+            tag,
+            BlindIndexKey::gen_key()
+                .unwrap()
+                .index("email", b"alice@example.com", 32)
+                .unwrap()
+        );
+        assert_ne!(
+            key.compound_index("i", &[b"ab", b"c"], 32).unwrap(),
+            key.compound_index("i", &[b"a", b"bc"], 32).unwrap()
+        );
+        assert!(key.index("email", b"x", 0).is_err());
+        assert!(key.index("email", b"x", 33).is_err());
+    }
+
+    #[test]
+    fn test_key_serialization() {
+        let key = BlindIndexKey::gen_key().unwrap();
+        let deser = BlindIndexKey::from_str(&key.serialize_to_string()).unwrap();
+        assert_eq!(deser.key_bytes(), key.key_bytes());
+    }
+
+    crate::serde_support::test_derive_serde!(BlindIndexKey);
+}
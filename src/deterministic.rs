@@ -4,9 +4,15 @@ use aes::{
 };
 use anyhow::Result;
 use generic_array::{sequence::Split, typenum::U32, GenericArray};
+use rayon::prelude::*;
 
 use crate::key_util::*;
 
+// Values per call into AES in the batch methods, and per rayon task in the
+// parallel ones.
+const BATCH_VALUES: usize = 64;
+const PAR_CHUNK_VALUES: usize = 4096;
+
 /// Used to deterministically encrypt 256-bit messages. Be aware of the security
 /// implications of deterministic encryption, in particular, that the encryption
 /// is deterministic and therefore cannot provide a security level that
@@ -43,6 +49,14 @@ impl KeyMaterial for DeterministicEncryptionSymmetricKey256 {
     }
 }
 
+impl DerivableKey for DeterministicEncryptionSymmetricKey256 {
+    const KDF_CONTEXT: &'static str = "esebdet";
+    const DERIVED_LEN: usize = 48;
+    fn from_derived(data: &[u8]) -> Result<DeterministicEncryptionSymmetricKey256> {
+        Self::from_slice(data)
+    }
+}
+
 impl std::str::FromStr for DeterministicEncryptionSymmetricKey256 {
     type Err = anyhow::Error;
     fn from_str(data: &str) -> Result<DeterministicEncryptionSymmetricKey256> {
@@ -77,10 +91,87 @@ impl DeterministicEncryptionSymmetricKey256 {
         cleartext
     }
 
-    fn from_slice(slice: &[u8]) -> Result<DeterministicEncryptionSymmetricKey256> {
-        if slice.len() != 48 {
-            anyhow::bail!("Keys must be exactly 48 bytes.");
+    /// Same as `encrypt` on each value, but many AES blocks at a time.
+    pub fn encrypt_batch(&self, cleartexts: &[[u8; 32]]) -> Vec<[u8; 32]> {
+        let mut v = cleartexts.to_vec();
+        self.encrypt_in_place(&mut v);
+        v
+    }
+
+    pub fn decrypt_batch(&self, crypttexts: &[[u8; 32]]) -> Vec<[u8; 32]> {
+        let mut v = crypttexts.to_vec();
+        self.decrypt_in_place(&mut v);
+        v
+    }
+
+    pub fn encrypt_in_place(&self, values: &mut [[u8; 32]]) {
+        for chunk in values.chunks_mut(BATCH_VALUES) {
+            for value in chunk.iter_mut() {
+                for (byte, iv) in value.iter_mut().zip(&self.iv) {
+                    *byte ^= iv;
+                }
+            }
+            self.apply_blocks(chunk, |aes, blocks| aes.encrypt_blocks(blocks));
+        }
+    }
+
+    pub fn decrypt_in_place(&self, values: &mut [[u8; 32]]) {
+        for chunk in values.chunks_mut(BATCH_VALUES) {
+            self.apply_blocks(chunk, |aes, blocks| aes.decrypt_blocks(blocks));
+            for value in chunk.iter_mut() {
+                for (byte, iv) in value.iter_mut().zip(&self.iv) {
+                    *byte ^= iv;
+                }
+            }
+        }
+    }
+
+    /// `encrypt_in_place` spread over the rayon thread pool.
+    pub fn par_encrypt_in_place(&self, values: &mut [[u8; 32]]) {
+        values
+            .par_chunks_mut(PAR_CHUNK_VALUES)
+            .for_each(|chunk| self.encrypt_in_place(chunk));
+    }
+
+    pub fn par_decrypt_in_place(&self, values: &mut [[u8; 32]]) {
+        values
+            .par_chunks_mut(PAR_CHUNK_VALUES)
+            .for_each(|chunk| self.decrypt_in_place(chunk));
+    }
+
+    pub fn par_encrypt_batch(&self, cleartexts: &[[u8; 32]]) -> Vec<[u8; 32]> {
+        let mut v = cleartexts.to_vec();
+        self.par_encrypt_in_place(&mut v);
+        v
+    }
+
+    pub fn par_decrypt_batch(&self, crypttexts: &[[u8; 32]]) -> Vec<[u8; 32]> {
+        let mut v = crypttexts.to_vec();
+        self.par_decrypt_in_place(&mut v);
+        v
+    }
+
+    // Runs `f` on the AES blocks of up to BATCH_VALUES values at once, so the
+    // cipher can pipeline them.
+    fn apply_blocks<F>(&self, values: &mut [[u8; 32]], f: F)
+    where
+        F: Fn(&Aes256, &mut [aes::Block]),
+    {
+        let mut blocks = [aes::Block::default(); 2 * BATCH_VALUES];
+        let blocks = &mut blocks[..2 * values.len()];
+        for (value, pair) in values.iter().zip(blocks.chunks_mut(2)) {
+            pair[0].copy_from_slice(&value[..16]);
+            pair[1].copy_from_slice(&value[16..]);
         }
+        f(&self.aes, blocks);
+        for (value, pair) in values.iter_mut().zip(blocks.chunks(2)) {
+            value[..16].copy_from_slice(&pair[0]);
+            value[16..].copy_from_slice(&pair[1]);
+        }
+    }
+
+    fn from_slice(slice: &[u8]) -> Result<DeterministicEncryptionSymmetricKey256> {
+        check_length(slice, 48)?;
         let (aes_key, iv) = slice.split_at(32);
         let aes_key: GenericArray<u8, U32> = *GenericArray::from_slice(aes_key);
         Ok(DeterministicEncryptionSymmetricKey256 {
@@ -119,6 +210,33 @@ mod tests {
         assert_eq!(cleartext, key.decrypt(crypttext));
     }
 
+    // Checked against an independent Python implementation of the same
+    // construction as `WideBlockEncryptionKey256`.
+    #[test]
+    fn test_wide_block_known_answers() {
+        let key_bytes: Vec<u8> = (0..32).collect();
+        let key = crate::WideBlockEncryptionKey256::from_slice(&key_bytes).unwrap();
+        for (cleartext, expected) in [
+            (
+                &b"THE SUN THE SUN THE SUN THE SUN "[..],
+                "10ddb409ff4e4748968b00c9187da5700dd48552c73eabc6a76da355fe5a6e81",
+            ),
+            (
+                &[0; 32][..],
+                "70f65e8405dacc2bfd8427d2617969d766b88abdf99b5c222d81208289491c34",
+            ),
+            (
+                &b"1192645332aaXysz!!924cbeeeeeeeee"[..],
+                "8e5874dcde2adf7e35db68062f4d3543545857aed860a67081de117f37fc2cdb",
+            ),
+        ] {
+            let cleartext = *GenericArray::from_slice(cleartext);
+            let crypttext = key.encrypt(cleartext);
+            assert_eq!(hex::encode(crypttext), expected);
+            assert_eq!(key.decrypt(crypttext), cleartext);
+        }
+    }
+
     /// Test that with an all-zero IV, and a key where the first 16 and second
     /// 16 bytes are identical, the first and second 16 bytes of the crypttext
     /// are also identical. We include this test to illustrate that these
@@ -139,6 +257,36 @@ mod tests {
         assert_eq!(cleartext, key.decrypt(crypttext));
     }
 
+    #[test]
+    fn test_batch_matches_single() {
+        let key = DeterministicEncryptionSymmetricKey256::gen_key().unwrap();
+        // Lengths around the batch and parallel chunk sizes.
+        for len in [0, 1, 63, 64, 65, 200, 4096 * 2 + 3] {
+            let values: Vec<[u8; 32]> = (0..len)
+                .map(|_| {
+                    sodiumoxide::randombytes::randombytes(32)
+                        .try_into()
+                        .unwrap()
+                })
+                .collect();
+            let expected: Vec<[u8; 32]> = values
+                .iter()
+                .map(|value| key.encrypt(*GenericArray::from_slice(value)).into())
+                .collect();
+
+            assert_eq!(key.encrypt_batch(&values), expected);
+            assert_eq!(key.par_encrypt_batch(&values), expected);
+            let mut in_place = values.clone();
+            key.encrypt_in_place(&mut in_place);
+            assert_eq!(in_place, expected);
+            key.par_decrypt_in_place(&mut in_place);
+            assert_eq!(in_place, values);
+
+            assert_eq!(key.decrypt_batch(&expected), values);
+            assert_eq!(key.par_decrypt_batch(&expected), values);
+        }
+    }
+
     crate::serde_support::test_derive_serde!(DeterministicEncryptionSymmetricKey256);
 
     // extern crate test;
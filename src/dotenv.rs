@@ -0,0 +1,141 @@
+use anyhow::{Context, Result};
+
+/// Parses a dotenv file into variables, in file order.
+///
+/// Lines are `NAME=value`, optionally prefixed with `export`. Blank lines and
+/// lines starting with `#` are skipped, as is a `#` comment after whitespace
+/// in an unquoted value. Single quoted values are taken literally; double
+/// quoted ones understand `\n`, `\t`, `\r`, `\"`, `\\` and `\$`. Quoted values
+/// may span lines. Nothing is expanded: `$HOME` stays `$HOME`.
+pub fn parse_dotenv(data: &str) -> Result<Vec<(String, String)>> {
+    let mut vars = Vec::default();
+    let mut lines = data.lines().enumerate();
+    while let Some((i, line)) = lines.next() {
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line
+            .strip_prefix("export ")
+            .map(str::trim_start)
+            .unwrap_or(line);
+        let (name, rest) = line
+            .split_once('=')
+            .with_context(|| format!("line {}: expected NAME=value", i + 1))?;
+        let name = name.trim_end();
+        if !is_valid_name(name) {
+            anyhow::bail!("line {}: bad variable name {:?}", i + 1, name);
+        }
+        let value = match rest.trim_start() {
+            quoted if quoted.starts_with(['"', '\'']) => {
+                let quote = quoted.chars().next().expect("starts with a quote");
+                parse_quoted(quote, &quoted[1..], &mut lines)
+                    .with_context(|| format!("line {}: value of {}", i + 1, name))?
+            }
+            _ => {
+                let end = rest
+                    .char_indices()
+                    .find(|(j, c)| *c == '#' && rest[..*j].ends_with(char::is_whitespace))
+                    .map_or(rest.len(), |(j, _)| j);
+                rest[..end].trim().to_string()
+            }
+        };
+        vars.push((name.to_string(), value));
+    }
+    Ok(vars)
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+// `text` follows the opening quote. Further lines are taken from `lines`
+// until the closing quote.
+fn parse_quoted<'a, I>(quote: char, mut text: &'a str, lines: &mut I) -> Result<String>
+where
+    I: Iterator<Item = (usize, &'a str)>,
+{
+    let mut value = String::default();
+    loop {
+        let mut chars = text.char_indices();
+        while let Some((i, c)) = chars.next() {
+            if c == quote {
+                let rest = text[i + 1..].trim_start();
+                if !rest.is_empty() && !rest.starts_with('#') {
+                    anyhow::bail!("unexpected {:?} after the closing quote", rest);
+                }
+                return Ok(value);
+            } else if c == '\\' && quote == '"' {
+                match chars.next().map(|(_, c)| c) {
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some('r') => value.push('\r'),
+                    Some(c @ ('"' | '\\' | '$')) => value.push(c),
+                    Some(c) => {
+                        value.push('\\');
+                        value.push(c);
+                    }
+                    None => value.push('\\'),
+                }
+            } else {
+                value.push(c);
+            }
+        }
+        value.push('\n');
+        text = lines.next().context("no closing quote")?.1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(data: &str) -> Vec<(String, String)> {
+        parse_dotenv(data).unwrap()
+    }
+
+    fn var(name: &str, value: &str) -> (String, String) {
+        (name.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn test_parse() {
+        let data = r#"
+# Database
+export DB_HOST = db.example.com
+DB_PASSWORD='p@ss $literal \n' # trailing comment
+EMPTY=
+URL=http://example.com/#anchor
+COMMENTED=value # comment
+ESCAPED="a\tb\n\"c\" \$HOME \q"
+CERT="-----BEGIN-----
+abc
+-----END-----"
+"#;
+        assert_eq!(
+            parse(data),
+            vec![
+                var("DB_HOST", "db.example.com"),
+                var("DB_PASSWORD", "p@ss $literal \\n"),
+                var("EMPTY", ""),
+                var("URL", "http://example.com/#anchor"),
+                var("COMMENTED", "value"),
+                var("ESCAPED", "a\tb\n\"c\" $HOME \\q"),
+                var("CERT", "-----BEGIN-----\nabc\n-----END-----"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse_dotenv("NAME").is_err());
+        assert!(parse_dotenv("1NAME=x").is_err());
+        assert!(parse_dotenv("MY-NAME=x").is_err());
+        assert!(parse_dotenv("NAME=\"open\nnever closed").is_err());
+        let err = parse_dotenv("A=1\nB='x' y").unwrap_err();
+        assert!(format!("{:#}", err).starts_with("line 2: value of B"));
+        assert_eq!(parse("A=1\r\nB=2\r\n"), vec![var("A", "1"), var("B", "2")]);
+    }
+}
@@ -0,0 +1,267 @@
+use std::cell::RefCell;
+
+use anyhow::{Context, Error, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sodiumoxide::crypto::aead::xchacha20poly1305_ietf as aead;
+
+use crate::key_util::KeyMaterial;
+use crate::serde_support::{decode_tagged_str, deserialize_tagged_bytes, serialize_tagged_bytes};
+use crate::SymmetricKey;
+
+const HEADER: &str = "eseb1::ct::";
+
+// Context for the subkey that encrypts fields, so the same `SymmetricKey`
+// can also encrypt files.
+const FIELD_CONTEXT: &str = "esebfld";
+
+thread_local! {
+    static FIELD_KEY: RefCell<Option<aead::Key>> = const { RefCell::new(None) };
+}
+
+/// A struct field that is encrypted when serialized and decrypted when
+/// deserialized, e.g. `password: Encrypted<String>`.
+///
+/// The value is serialized to JSON, then encrypted with XChaCha20-Poly1305
+/// under a subkey of the `SymmetricKey` set with `with_field_key`. Human
+/// readable formats get an `eseb1::ct::` string, binary formats the bytes.
+/// (De)serializing without a key set is an error. Equal values encrypt
+/// differently every time.
+///
+/// A ciphertext is not tied to the field or row it was written to. With the
+/// same key, an `Encrypted<String>` copied from one row's `email` into
+/// another row's `ssn` decrypts without complaint. Where that matters, use
+/// `encrypt_to_string` and `decrypt_str` with a `context` naming the field
+/// and row, e.g. `b"users/42/ssn"`.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Encrypted<T>(pub T);
+
+impl<T> Encrypted<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> From<T> for Encrypted<T> {
+    fn from(value: T) -> Encrypted<T> {
+        Encrypted(value)
+    }
+}
+
+impl<T> std::ops::Deref for Encrypted<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> std::ops::DerefMut for Encrypted<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+// Keep the cleartext out of logs.
+impl<T> std::fmt::Debug for Encrypted<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Encrypted(..)")
+    }
+}
+
+impl<T: Serialize> Encrypted<T> {
+    /// Encrypts under `key` to an `eseb1::ct::` string, without
+    /// `with_field_key`. The same `context` is needed to decrypt; serde uses
+    /// an empty one.
+    pub fn encrypt_to_string(&self, key: &SymmetricKey, context: &[u8]) -> Result<String> {
+        let ciphertext = seal(&self.0, &field_key(key), context)?;
+        Ok(format!("{}{}", HEADER, base64::encode(ciphertext)))
+    }
+}
+
+impl<T: DeserializeOwned> Encrypted<T> {
+    /// Decrypts an `eseb1::ct::` string under `key` and `context`, without
+    /// `with_field_key`.
+    pub fn decrypt_str(data: &str, key: &SymmetricKey, context: &[u8]) -> Result<Encrypted<T>> {
+        let data = decode_tagged_str(data, HEADER).context("encrypted field")?;
+        open(&data, &field_key(key), context)
+    }
+}
+
+/// Runs `f` with `key` as this thread's key for `Encrypted` fields, then puts
+/// back the previous one, if any.
+pub fn with_field_key<R, F: FnOnce() -> R>(key: &SymmetricKey, f: F) -> R {
+    struct Restore(Option<aead::Key>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            FIELD_KEY.with(|k| *k.borrow_mut() = self.0.take());
+        }
+    }
+
+    let key = field_key(key);
+    let _restore = Restore(FIELD_KEY.with(|k| k.borrow_mut().replace(key)));
+    f()
+}
+
+fn field_key(key: &SymmetricKey) -> aead::Key {
+    let subkey = key
+        .derive(FIELD_CONTEXT, 0)
+        .expect("valid field key context");
+    aead::Key::from_slice(&subkey.key_bytes()).expect("same key length")
+}
+
+fn current_key() -> Result<aead::Key> {
+    FIELD_KEY
+        .with(|k| k.borrow().clone())
+        .context("no key for Encrypted fields; use eseb::with_field_key")
+}
+
+// The nonce, then the ciphertext. The associated data is the header, then
+// `context`.
+fn seal<T: Serialize>(value: &T, key: &aead::Key, context: &[u8]) -> Result<Vec<u8>> {
+    let mut cleartext = serde_json::to_vec(value).context("serialize encrypted field")?;
+    let nonce = aead::gen_nonce();
+    let mut v = nonce.as_ref().to_vec();
+    v.extend(aead::seal(
+        &cleartext,
+        Some(&[HEADER.as_bytes(), context].concat()),
+        &nonce,
+        key,
+    ));
+    sodiumoxide::utils::memzero(&mut cleartext);
+    Ok(v)
+}
+
+fn open<T: DeserializeOwned>(data: &[u8], key: &aead::Key, context: &[u8]) -> Result<Encrypted<T>> {
+    if data.len() < aead::NONCEBYTES + aead::TAGBYTES {
+        anyhow::bail!("encrypted field too short");
+    }
+    let (nonce, ciphertext) = data.split_at(aead::NONCEBYTES);
+    let nonce = aead::Nonce::from_slice(nonce).expect("checked length");
+    let ad = [HEADER.as_bytes(), context].concat();
+    let mut cleartext = aead::open(ciphertext, Some(&ad), &nonce, key)
+        .map_err(|_| Error::msg("encrypted field failed authentication"))?;
+    let value = serde_json::from_slice(&cleartext).context("deserialize encrypted field");
+    sodiumoxide::utils::memzero(&mut cleartext);
+    Ok(Encrypted(value?))
+}
+
+impl<T: Serialize> Serialize for Encrypted<T> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::ser::Serializer,
+    {
+        use serde::ser::Error;
+        let ciphertext = current_key()
+            .and_then(|key| seal(&self.0, &key, b""))
+            .map_err(|e| S::Error::custom(format!("{:#}", e)))?;
+        serialize_tagged_bytes(serializer, HEADER, &ciphertext)
+    }
+}
+
+impl<'de, T: DeserializeOwned> serde::Deserialize<'de> for Encrypted<T> {
+    fn deserialize<D>(deserializer: D) -> Result<Encrypted<T>, D::Error>
+    where
+        D: serde::de::Deserializer<'de>,
+    {
+        use serde::de::Error;
+        let data = deserialize_tagged_bytes(deserializer, HEADER)?;
+        current_key()
+            .and_then(|key| open(&data, &key, b""))
+            .map_err(|e| D::Error::custom(format!("{:#}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Row {
+        id: u64,
+        email: Encrypted<String>,
+        scores: Encrypted<Vec<u32>>,
+    }
+
+    fn row() -> Row {
+        Row {
+            id: 7,
+            email: "alice@example.com".to_string().into(),
+            scores: vec![1, 2, 3].into(),
+        }
+    }
+
+    #[test]
+    fn test_json() {
+        let key = SymmetricKey::gen_key().unwrap();
+        let json = with_field_key(&key, || serde_json::to_string(&row()).unwrap());
+        assert!(json.contains("\"email\":\"eseb1::ct::"));
+        assert!(!json.contains("alice"));
+        let parsed: Row = with_field_key(&key, || serde_json::from_str(&json).unwrap());
+        assert_eq!(parsed, row());
+
+        // Fresh nonces every time.
+        let again = with_field_key(&key, || serde_json::to_string(&row()).unwrap());
+        assert_ne!(json, again);
+
+        let other = SymmetricKey::gen_key().unwrap();
+        let err = with_field_key(&other, || serde_json::from_str::<Row>(&json).unwrap_err());
+        assert!(err.to_string().contains("authentication"));
+    }
+
+    #[test]
+    fn test_bincode() {
+        let key = SymmetricKey::gen_key().unwrap();
+        let data = with_field_key(&key, || bincode::serialize(&row()).unwrap());
+        let parsed: Row = with_field_key(&key, || bincode::deserialize(&data).unwrap());
+        assert_eq!(parsed, row());
+    }
+
+    #[test]
+    fn test_explicit_key() {
+        let key = SymmetricKey::gen_key().unwrap();
+        let field = Encrypted(42u64);
+        let s = field.encrypt_to_string(&key, b"").unwrap();
+        assert!(s.starts_with(HEADER));
+        assert_eq!(Encrypted::<u64>::decrypt_str(&s, &key, b"").unwrap(), field);
+        // Interchangeable with the serde form.
+        let json = format!("{:?}", s);
+        let parsed: Encrypted<u64> = with_field_key(&key, || serde_json::from_str(&json).unwrap());
+        assert_eq!(parsed, field);
+        assert!(Encrypted::<u64>::decrypt_str("eseb1::ct::AAAA", &key, b"").is_err());
+        assert!(Encrypted::<u64>::decrypt_str(&s[1..], &key, b"").is_err());
+    }
+
+    #[test]
+    fn test_context() {
+        let key = SymmetricKey::gen_key().unwrap();
+        let ssn = Encrypted("123-45-6789".to_string());
+        let s = ssn.encrypt_to_string(&key, b"users/42/ssn").unwrap();
+        assert_eq!(
+            Encrypted::<String>::decrypt_str(&s, &key, b"users/42/ssn").unwrap(),
+            ssn
+        );
+        assert!(Encrypted::<String>::decrypt_str(&s, &key, b"users/42/email").is_err());
+        assert!(Encrypted::<String>::decrypt_str(&s, &key, b"").is_err());
+        let parsed = with_field_key(&key, || {
+            serde_json::from_str::<Encrypted<String>>(&format!("{:?}", s))
+        });
+        assert!(parsed.is_err());
+    }
+
+    #[test]
+    fn test_key_scope() {
+        assert!(serde_json::to_string(&row()).is_err());
+        let outer = SymmetricKey::gen_key().unwrap();
+        let inner = SymmetricKey::gen_key().unwrap();
+        let json = with_field_key(&outer, || {
+            with_field_key(&inner, || ());
+            serde_json::to_string(&row()).unwrap()
+        });
+        assert!(serde_json::to_string(&row()).is_err());
+        let parsed: Row = with_field_key(&outer, || serde_json::from_str(&json).unwrap());
+        assert_eq!(parsed, row());
+        assert_eq!(format!("{:?}", parsed.email), "Encrypted(..)");
+    }
+}
@@ -6,6 +6,54 @@ use std::io::Read;
 
 use crate::SymmetricKey;
 
+/// Why decrypting a stream failed, for the failures worth telling apart.
+/// Decryption errors carry this as context, so use
+/// `downcast_ref::<StreamError>()` to find it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamError {
+    /// The first record is not a secretstream header.
+    BadHeader,
+    /// A record failed authentication: the wrong key, or corrupt data.
+    Authentication,
+    /// The stream ends before its Final tag.
+    Truncated,
+    /// There are records after the Final tag.
+    TrailingData,
+    /// The cleartext is not valid brotli, although it authenticated.
+    Decompress,
+    /// A length prefix asks for a record longer than any eseb writes.
+    RecordTooLarge,
+}
+
+impl StreamError {
+    /// Short name for reports, e.g. `authentication`.
+    pub fn class(&self) -> &'static str {
+        match self {
+            StreamError::BadHeader => "bad-header",
+            StreamError::Authentication => "authentication",
+            StreamError::Truncated => "truncated",
+            StreamError::TrailingData => "trailing-data",
+            StreamError::Decompress => "decompress",
+            StreamError::RecordTooLarge => "record-too-large",
+        }
+    }
+}
+
+impl std::fmt::Display for StreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StreamError::BadHeader => write!(f, "parse stream header"),
+            StreamError::Authentication => write!(f, "decrypt chunk"),
+            StreamError::Truncated => write!(f, "stream truncated: missing Final tag"),
+            StreamError::TrailingData => write!(f, "unexpected data after the end of the stream"),
+            StreamError::Decompress => write!(f, "decompress"),
+            StreamError::RecordTooLarge => write!(f, "record too large"),
+        }
+    }
+}
+
+impl std::error::Error for StreamError {}
+
 pub struct EncryptingRecordWriter<O: RecordWriter> {
     inner: Option<O>,
     stream: secretstream::Stream<secretstream::Push>,
@@ -31,8 +79,18 @@ impl<O: RecordWriter> DecryptingRecordWriter<O> {
         })
     }
 
+    /// Fails unless the stream ended with its Final tag, i.e. the whole
+    /// message was written and verified.
     #[must_use]
     pub fn into_inner(mut self) -> Result<O> {
+        match self.inner.as_ref().context("already called finish")? {
+            (_, DecryptState::Finished, _) => (),
+            _ => {
+                // Don't let drop write out the unfinished message.
+                self.inner = None;
+                return Err(StreamError::Truncated.into());
+            }
+        }
         self.into_inner_internal()?;
         Ok(self.inner.take().expect("").0)
     }
@@ -63,7 +121,8 @@ impl<O: RecordWriter> DecryptingRecordWriter<O> {
 
         if compress {
             let mut v = Vec::default();
-            brotli::BrotliDecompress(&mut data.as_slice(), &mut v).context("decompress")?;
+            brotli::BrotliDecompress(&mut data.as_slice(), &mut v)
+                .context(StreamError::Decompress)?;
             writer.write_record(&v)?;
         } else {
             writer.write_record(&data)?;
@@ -80,7 +139,7 @@ impl<O: RecordWriter> RecordWriter for DecryptingRecordWriter<O> {
         match self.inner.take().context("already called finish")? {
             (writer, DecryptState::WantHeader(key), buf) => {
                 let header = secretstream::xchacha20poly1305::Header::from_slice(data)
-                    .context("parse stream header")?;
+                    .context(StreamError::BadHeader)?;
 
                 let stream = secretstream::Stream::init_pull(&header, key.as_ref())
                     .ok()
@@ -93,7 +152,10 @@ impl<O: RecordWriter> RecordWriter for DecryptingRecordWriter<O> {
                     anyhow::bail!("stream marked finalized without Final tag");
                 }
 
-                let (mut cleartext, tag) = stream.pull(data, None).ok().context("decrypt chunk")?;
+                let (mut cleartext, tag) = stream
+                    .pull(data, None)
+                    .ok()
+                    .context(StreamError::Authentication)?;
                 match tag {
                     secretstream::Tag::Final => {
                         if !cleartext.is_empty() || !buf.is_empty() {
@@ -118,7 +180,8 @@ impl<O: RecordWriter> RecordWriter for DecryptingRecordWriter<O> {
             }
             (writer, DecryptState::Finished, buf) => {
                 self.inner = Some((writer, DecryptState::Finished, buf));
-                anyhow::bail!("write_record called after finished");
+                return Err(StreamError::TrailingData)
+                    .context("write_record called after finished");
             }
         }
 
@@ -281,7 +344,10 @@ impl<I: RecordReader> DecryptingRecordReader<I> {
         // message we return, hence the lack of somet kind of VecBuilder.
         buf.clear();
         while let Some(data) = reader.maybe_read_record().context("read record")? {
-            let (mut cleartext, tag) = stream.pull(data, None).ok().context("decrypt chunk")?;
+            let (mut cleartext, tag) = stream
+                .pull(data, None)
+                .ok()
+                .context(StreamError::Authentication)?;
 
             match tag {
                 secretstream::Tag::Final => {
@@ -299,15 +365,21 @@ impl<I: RecordReader> DecryptingRecordReader<I> {
             }
         }
 
-        Ok((None, Vec::default()))
+        // Without this, a stream cut off between records would decrypt as a
+        // shorter, valid looking message.
+        Err(StreamError::Truncated.into())
     }
 
     fn take_stream(&mut self) -> Result<Option<secretstream::Stream<secretstream::Pull>>> {
         match std::mem::take(&mut self.stream) {
             DecryptingRecordReaderState::PreInit => {
-                let data = self.inner.read_record().context("read header")?;
+                let data = self
+                    .inner
+                    .maybe_read_record()
+                    .context("read header")?
+                    .context(StreamError::Truncated)?;
                 let header = secretstream::xchacha20poly1305::Header::from_slice(&data)
-                    .context("parse stream header")?;
+                    .context(StreamError::BadHeader)?;
 
                 let stream = secretstream::Stream::init_pull(&header, self.key.as_ref())
                     .ok()
@@ -340,7 +412,9 @@ impl<I: RecordReader> RecordReader for DecryptingRecordReader<I> {
         };
 
         if self.buf.is_empty() && cleartext.is_empty() {
-            match std::mem::take(&mut self.stream) {
+            // Leave the state in place so that the next call picks up where
+            // this one left off.
+            match &self.stream {
                 DecryptingRecordReaderState::PreInit => {
                     unreachable!()
                 }
@@ -358,7 +432,8 @@ impl<I: RecordReader> RecordReader for DecryptingRecordReader<I> {
 
             if self.compress {
                 let v = std::mem::take(&mut self.buf);
-                brotli::BrotliDecompress(&mut v.as_slice(), &mut self.buf).context("decompress")?;
+                brotli::BrotliDecompress(&mut v.as_slice(), &mut self.buf)
+                    .context(StreamError::Decompress)?;
             }
 
             Ok(Some(&self.buf[..]))
@@ -549,4 +624,57 @@ mod tests {
         chunk_test(vec![b"", b"dead of ", b""]);
         chunk_test(vec![b"", b"", b" night "]);
     }
+
+    #[test]
+    fn test_uncompressed_chunks() {
+        let chunks: [&[u8]; 3] = [b"", b"pumpkins scream", b""];
+        let key = SymmetricKey::gen_key().unwrap();
+        let mut crypt_writer = EncryptingRecordWriter::new(
+            BufferRecordWriter::new(Format::Record32),
+            key.clone(),
+            false,
+        )
+        .unwrap();
+        for chunk in chunks.iter() {
+            crypt_writer.write_record(chunk).unwrap();
+        }
+
+        let mut clear_reader = decrypt(crypt_writer, key, false);
+        for chunk in chunks.iter() {
+            assert_eq!(chunk, &clear_reader.read_record().unwrap());
+        }
+        assert!(clear_reader.maybe_read_record().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_truncated_stream() {
+        let key = SymmetricKey::gen_key().unwrap();
+        let mut crypt_writer = EncryptingRecordWriter::new(
+            BufferRecordWriter::new(Format::Record32),
+            key.clone(),
+            false,
+        )
+        .unwrap();
+        crypt_writer.write_record(b"this is").unwrap();
+        crypt_writer.write_record(b"halloween").unwrap();
+        let ciphertext = crypt_writer.inner.take().unwrap().into_cow();
+
+        let cipher_reader =
+            BufferRecordReader::new(ciphertext.clone(), Format::Record32, std::u32::MAX as usize);
+        let mut clear_reader =
+            DecryptingRecordReader::new(cipher_reader, key.clone(), false).unwrap();
+        assert_eq!(clear_reader.read_record().unwrap(), b"this is");
+        assert_eq!(clear_reader.read_record().unwrap(), b"halloween");
+        assert!(clear_reader.maybe_read_record().is_err());
+
+        let mut cipher_reader =
+            BufferRecordReader::new(ciphertext, Format::Record32, std::u32::MAX as usize);
+        let mut clear_writer =
+            DecryptingRecordWriter::new(BufferRecordWriter::new(Format::Record32), key, false)
+                .unwrap();
+        while let Some(rec) = cipher_reader.maybe_read_record().unwrap() {
+            clear_writer.write_record(rec).unwrap();
+        }
+        assert!(clear_writer.into_inner().is_err());
+    }
 }
@@ -0,0 +1,236 @@
+use std::fmt::Write;
+use std::io::Read;
+
+use anyhow::{Context, Result};
+use record_reader::{Format, RecordReader};
+use serde::Serialize;
+use sodiumoxide::crypto::secretstream;
+
+use crate::{BoundedRecordReader, PassphraseParams};
+
+/// What can be learned about an encrypted file without its key. See
+/// `inspect_file`.
+#[derive(Clone, Debug, Serialize)]
+pub struct FileInfo {
+    /// `record`, or `record32` for the legacy format.
+    pub framing: &'static str,
+    /// Argon2id parameters, if the key is derived from a passphrase.
+    pub passphrase: Option<PassphraseInfo>,
+    /// The secretstream header, in hex.
+    pub stream_header: String,
+    /// Encrypted records after the header, including the final one.
+    pub records: u64,
+    pub record_sizes: RecordSizes,
+    pub file_bytes: u64,
+    /// Bytes of (possibly compressed) cleartext the records hold.
+    pub payload_bytes: u64,
+    /// Everything else: framing, headers and authentication tags.
+    pub overhead_bytes: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PassphraseInfo {
+    pub opslimit: u64,
+    pub memlimit: u64,
+    pub salt: String,
+}
+
+/// Sizes of the encrypted records, tags included.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RecordSizes {
+    pub min: u64,
+    pub max: u64,
+    pub mean: u64,
+    /// Record counts by size, in power of two buckets.
+    pub histogram: Vec<Bucket>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Bucket {
+    /// Records in this bucket are at most this many bytes, and more than
+    /// half of it.
+    pub up_to: u64,
+    pub count: u64,
+}
+
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+fn to_hex(data: &[u8]) -> String {
+    let mut v = String::with_capacity(data.len() * 2);
+    for byte in data {
+        write!(v, "{:02x}", byte).expect("error writing to string");
+    }
+    v
+}
+
+/// Describes an encrypted file from its framing and headers alone. Nothing is
+/// decrypted, so none of this is authenticated, and whether the file is
+/// complete cannot be known.
+///
+/// The file is read once, holding one record at a time.
+pub fn inspect_file<R: Read>(reader: R) -> Result<FileInfo> {
+    let (format, reader) = crate::detect_format(reader)?;
+    let format = format.context("not an eseb encrypted file")?;
+    let mut reader = CountingReader {
+        inner: reader,
+        count: 0,
+    };
+    let mut records = BoundedRecordReader::new(&mut reader, format);
+
+    let mut first = records.read_record().context("read header")?;
+    let passphrase = if PassphraseParams::is_header(first) {
+        let params = PassphraseParams::from_bytes(first)?;
+        let info = PassphraseInfo {
+            opslimit: params.opslimit(),
+            memlimit: params.memlimit(),
+            salt: to_hex(params.salt()),
+        };
+        first = records.read_record().context("read stream header")?;
+        Some(info)
+    } else {
+        None
+    };
+    if first.len() != secretstream::HEADERBYTES {
+        anyhow::bail!("bad stream header length {}", first.len());
+    }
+    let stream_header = to_hex(first);
+
+    let mut count = 0;
+    let mut total = 0;
+    let mut payload_bytes = 0;
+    let mut sizes = RecordSizes {
+        min: u64::MAX,
+        ..RecordSizes::default()
+    };
+    // Index i counts records of at most 2^i bytes.
+    let mut buckets = [0u64; 65];
+    while let Some(rec) = records.maybe_read_record().context("read record")? {
+        let len = rec.len() as u64;
+        if len < secretstream::ABYTES as u64 {
+            anyhow::bail!("record {} is too short to be encrypted", count + 1);
+        }
+        count += 1;
+        total += len;
+        payload_bytes += len - secretstream::ABYTES as u64;
+        sizes.min = sizes.min.min(len);
+        sizes.max = sizes.max.max(len);
+        buckets[len.next_power_of_two().trailing_zeros() as usize] += 1;
+    }
+    drop(records);
+
+    if count == 0 {
+        sizes.min = 0;
+    }
+    sizes.mean = total.checked_div(count).unwrap_or(0);
+    sizes.histogram = buckets
+        .iter()
+        .enumerate()
+        .filter(|(_, count)| **count > 0)
+        .map(|(i, count)| Bucket {
+            up_to: 1 << i,
+            count: *count,
+        })
+        .collect();
+
+    Ok(FileInfo {
+        framing: match format {
+            Format::Record32 => "record32",
+            _ => "record",
+        },
+        passphrase,
+        stream_header,
+        records: count,
+        record_sizes: sizes,
+        file_bytes: reader.count,
+        payload_bytes,
+        overhead_bytes: reader.count - payload_bytes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{passphrase_encrypt_sign_file, symmetric_encrypt_sign, SymmetricKey};
+
+    #[test]
+    fn test_inspect() {
+        let key = SymmetricKey::gen_key().unwrap();
+        let ciphertext =
+            symmetric_encrypt_sign(&key, b"some text", false, Format::Record32).unwrap();
+        let info = inspect_file(&*ciphertext).unwrap();
+        assert_eq!(info.framing, "record32");
+        assert!(info.passphrase.is_none());
+        assert_eq!(
+            info.stream_header,
+            to_hex(&ciphertext[4..4 + secretstream::HEADERBYTES])
+        );
+        // One record of text and an empty final one.
+        assert_eq!(info.records, 2);
+        assert_eq!(info.record_sizes.min, secretstream::ABYTES as u64);
+        assert_eq!(info.record_sizes.max, 9 + secretstream::ABYTES as u64);
+        assert_eq!(info.file_bytes, ciphertext.len() as u64);
+        assert_eq!(info.payload_bytes, 9);
+        assert_eq!(info.overhead_bytes, ciphertext.len() as u64 - 9);
+        let histogram: Vec<_> = info
+            .record_sizes
+            .histogram
+            .iter()
+            .map(|bucket| (bucket.up_to, bucket.count))
+            .collect();
+        assert_eq!(histogram, vec![(32, 2)]);
+
+        // Cut off after the stream header.
+        let info = inspect_file(&ciphertext[..4 + secretstream::HEADERBYTES]).unwrap();
+        assert_eq!(info.records, 0);
+        assert_eq!(info.record_sizes.min, 0);
+        assert_eq!(info.record_sizes.mean, 0);
+
+        // A length prefix of 4 GiB is refused, not allocated.
+        let mut huge = ciphertext[..4 + secretstream::HEADERBYTES].to_vec();
+        huge.extend_from_slice(&[0xff; 4]);
+        let e = inspect_file(&*huge).unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<crate::StreamError>(),
+            Some(&crate::StreamError::RecordTooLarge)
+        );
+    }
+
+    #[test]
+    fn test_inspect_passphrase() {
+        let params = PassphraseParams::interactive().unwrap();
+        let mut ciphertext = Vec::default();
+        passphrase_encrypt_sign_file(
+            b"pw",
+            &params,
+            &b"text"[..],
+            &mut ciphertext,
+            false,
+            Format::Record,
+        )
+        .unwrap();
+        let info = inspect_file(&*ciphertext).unwrap();
+        assert_eq!(info.framing, "record");
+        let passphrase = info.passphrase.unwrap();
+        assert_eq!(passphrase.opslimit, params.opslimit());
+        assert_eq!(passphrase.salt, to_hex(params.salt()));
+        assert_eq!(info.payload_bytes, 4);
+    }
+
+    #[test]
+    fn test_inspect_rejects_other_files() {
+        assert!(inspect_file(&b""[..]).is_err());
+        assert!(inspect_file(&b"plain text, not encrypted"[..]).is_err());
+    }
+}
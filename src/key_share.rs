@@ -0,0 +1,299 @@
+use anyhow::{Error, Result};
+
+use crate::key_util::*;
+use crate::AnyKey;
+
+const SET_ID_LEN: usize = 8;
+const PREFIX_LEN: usize = 2 + SET_ID_LEN;
+
+/// One share of a key split with Shamir's secret sharing over GF(256). Any
+/// `threshold` shares of the same set rebuild the key; fewer reveal nothing
+/// about it.
+///
+/// What is split is the serialized key string, so the key type comes back
+/// along with the key.
+#[derive(Clone)]
+pub struct KeyShare {
+    threshold: u8,
+    index: u8,
+    set_id: [u8; SET_ID_LEN],
+    data: Vec<u8>,
+}
+
+crate::serde_support::derive_serde!(KeyShare, KeyShareVisitor);
+
+impl std::str::FromStr for KeyShare {
+    type Err = anyhow::Error;
+    fn from_str(data: &str) -> Result<KeyShare> {
+        let key_data = parse_header(data.trim(), &Self::HEADER)?;
+        if key_data.len() <= PREFIX_LEN {
+            return Err(KeyParseError::InvalidPayload("share too short".to_string()).into());
+        }
+        let share = KeyShare {
+            threshold: key_data[0],
+            index: key_data[1],
+            set_id: key_data[2..PREFIX_LEN].try_into().expect("checked length"),
+            data: key_data[PREFIX_LEN..].to_vec(),
+        };
+        if share.threshold < 2 || share.index == 0 {
+            return Err(KeyParseError::InvalidPayload("bad share header".to_string()).into());
+        }
+        Ok(share)
+    }
+}
+
+impl KeyShare {
+    /// Splits `key` into `shares` shares, any `threshold` of which rebuild it.
+    pub fn split<K: KeyMaterial>(key: &K, threshold: u8, shares: u8) -> Result<Vec<KeyShare>> {
+        Self::split_string(&key.serialize_to_string(), threshold, shares)
+    }
+
+    pub(crate) fn split_string(key: &str, threshold: u8, shares: u8) -> Result<Vec<KeyShare>> {
+        if threshold < 2 {
+            anyhow::bail!("threshold must be at least 2");
+        }
+        if shares < threshold {
+            anyhow::bail!("cannot split into fewer shares than the threshold");
+        }
+
+        let set_id = sodiumoxide::randombytes::randombytes(SET_ID_LEN)
+            .try_into()
+            .expect("requested length");
+        let mut v: Vec<KeyShare> = (1..=shares)
+            .map(|index| KeyShare {
+                threshold,
+                index,
+                set_id,
+                data: Vec::with_capacity(key.len()),
+            })
+            .collect();
+
+        // One random polynomial of degree threshold - 1 per byte, with the
+        // secret byte as its constant term.
+        let mut coefficients = vec![0; threshold as usize];
+        for byte in key.bytes() {
+            coefficients[0] = byte;
+            sodiumoxide::randombytes::randombytes_into(&mut coefficients[1..]);
+            for share in &mut v {
+                share.data.push(gf256::evaluate(&coefficients, share.index));
+            }
+        }
+        sodiumoxide::utils::memzero(&mut coefficients);
+
+        Ok(v)
+    }
+
+    /// Rebuilds the key from at least `threshold` shares of one set. Shares
+    /// beyond the threshold are checked against the others.
+    pub fn combine(shares: &[KeyShare]) -> Result<AnyKey> {
+        let first = shares.first().ok_or_else(|| Error::msg("no shares"))?;
+        for share in shares {
+            if share.set_id != first.set_id
+                || share.threshold != first.threshold
+                || share.data.len() != first.data.len()
+            {
+                anyhow::bail!("shares are from different splits");
+            }
+        }
+        for (i, share) in shares.iter().enumerate() {
+            if shares[..i].iter().any(|other| other.index == share.index) {
+                anyhow::bail!("share {} given twice", share.index);
+            }
+        }
+        if shares.len() < first.threshold as usize {
+            anyhow::bail!(
+                "need {} shares to rebuild the key, got {}",
+                first.threshold,
+                shares.len()
+            );
+        }
+
+        let (used, extra) = shares.split_at(first.threshold as usize);
+        let xs: Vec<u8> = used.iter().map(|share| share.index).collect();
+        let mut secret = Vec::with_capacity(first.data.len());
+        let mut ys = vec![0; used.len()];
+        for i in 0..first.data.len() {
+            for (y, share) in ys.iter_mut().zip(used) {
+                *y = share.data[i];
+            }
+            for share in extra {
+                if gf256::interpolate(&xs, &ys, share.index) != share.data[i] {
+                    anyhow::bail!("share {} does not match the others", share.index);
+                }
+            }
+            secret.push(gf256::interpolate(&xs, &ys, 0));
+        }
+
+        let key = std::str::from_utf8(&secret)
+            .map_err(|_| Error::msg("shares do not rebuild a key"))
+            .and_then(|key| key.parse::<AnyKey>());
+        sodiumoxide::utils::memzero(&mut secret);
+        key.map_err(|e| e.context("shares do not rebuild a key"))
+    }
+
+    pub fn threshold(&self) -> u8 {
+        self.threshold
+    }
+
+    /// Which share this is, from 1.
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+}
+
+impl KeyMaterial for KeyShare {
+    const HEADER: &'static str = "eseb1::share::";
+    fn key_bytes(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(PREFIX_LEN + self.data.len());
+        v.push(self.threshold);
+        v.push(self.index);
+        v.extend_from_slice(&self.set_id);
+        v.extend_from_slice(&self.data);
+        v
+    }
+}
+
+// Arithmetic in GF(2^8) with the AES polynomial, written without branches or
+// table lookups on secret data.
+mod gf256 {
+    pub(super) fn mul(mut a: u8, mut b: u8) -> u8 {
+        let mut product = 0;
+        for _ in 0..8 {
+            product ^= a & 0u8.wrapping_sub(b & 1);
+            a = (a << 1) ^ (0x1b & 0u8.wrapping_sub(a >> 7));
+            b >>= 1;
+        }
+        product
+    }
+
+    // a^254 = a^-1 for nonzero a.
+    pub(super) fn inv(a: u8) -> u8 {
+        let mut result = 1;
+        let mut power = a;
+        for bit in 0..8 {
+            if 254 >> bit & 1 == 1 {
+                result = mul(result, power);
+            }
+            power = mul(power, power);
+        }
+        result
+    }
+
+    pub(super) fn evaluate(coefficients: &[u8], x: u8) -> u8 {
+        coefficients
+            .iter()
+            .rev()
+            .fold(0, |acc, coefficient| mul(acc, x) ^ coefficient)
+    }
+
+    /// The value at `x` of the polynomial through `(xs[i], ys[i])`. The `xs`
+    /// must be distinct.
+    pub(super) fn interpolate(xs: &[u8], ys: &[u8], x: u8) -> u8 {
+        let mut v = 0;
+        for (i, (xi, yi)) in xs.iter().zip(ys).enumerate() {
+            let mut numerator = 1;
+            let mut denominator = 1;
+            for (j, xj) in xs.iter().enumerate() {
+                if i != j {
+                    numerator = mul(numerator, x ^ xj);
+                    denominator = mul(denominator, xi ^ xj);
+                }
+            }
+            v ^= mul(*yi, mul(numerator, inv(denominator)));
+        }
+        v
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use crate::{SnowKeyPair, SymmetricKey};
+
+    #[test]
+    fn test_gf256() {
+        assert_eq!(gf256::mul(0x57, 0x83), 0xc1);
+        assert_eq!(gf256::mul(0x57, 0x13), 0xfe);
+        for a in 1..=255 {
+            assert_eq!(gf256::mul(a, gf256::inv(a)), 1);
+        }
+    }
+
+    #[test]
+    fn test_split_combine() {
+        let key = SymmetricKey::gen_key().unwrap();
+        let shares = KeyShare::split(&key, 3, 5).unwrap();
+        assert_eq!(shares.len(), 5);
+        for a in 0..5 {
+            for b in 0..5 {
+                for c in 0..5 {
+                    if a == b || b == c || a == c {
+                        continue;
+                    }
+                    let subset = [shares[a].clone(), shares[b].clone(), shares[c].clone()];
+                    let combined: SymmetricKey =
+                        KeyShare::combine(&subset).unwrap().try_into().unwrap();
+                    assert_eq!(combined.key_bytes(), key.key_bytes());
+                }
+            }
+        }
+
+        let combined = KeyShare::combine(&shares).unwrap();
+        assert_eq!(combined.key_bytes(), key.key_bytes());
+        assert!(KeyShare::combine(&shares[..2]).is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let key = SnowKeyPair::gen_key().unwrap();
+        let shares: Vec<KeyShare> = KeyShare::split(&key, 2, 3)
+            .unwrap()
+            .iter()
+            .map(|share| KeyShare::from_str(&share.serialize_to_string()).unwrap())
+            .collect();
+        assert_eq!(shares[1].index(), 2);
+        assert_eq!(shares[1].threshold(), 2);
+        let combined: SnowKeyPair = KeyShare::combine(&shares[1..]).unwrap().try_into().unwrap();
+        assert_eq!(combined.key_bytes(), key.key_bytes());
+    }
+
+    #[test]
+    fn test_inconsistent_shares() {
+        let key = SymmetricKey::gen_key().unwrap();
+        let shares = KeyShare::split(&key, 2, 3).unwrap();
+        let other = KeyShare::split(&key, 2, 3).unwrap();
+
+        assert!(KeyShare::combine(&[shares[0].clone(), other[1].clone()]).is_err());
+        assert!(KeyShare::combine(&[shares[0].clone(), shares[0].clone()]).is_err());
+        assert!(KeyShare::combine(&[]).is_err());
+
+        // A corrupt share beyond the threshold is caught.
+        let mut bad = shares[2].clone();
+        bad.data[5] ^= 1;
+        assert!(KeyShare::combine(&[shares[0].clone(), shares[1].clone(), bad.clone()]).is_err());
+
+        // A corrupt share within the threshold rebuilds garbage, which fails
+        // to parse.
+        assert!(KeyShare::combine(&[shares[0].clone(), bad]).is_err());
+    }
+
+    #[test]
+    fn test_bad_parameters() {
+        let key = SymmetricKey::gen_key().unwrap();
+        assert!(KeyShare::split(&key, 1, 3).is_err());
+        assert!(KeyShare::split(&key, 4, 3).is_err());
+        assert!(KeyShare::split(&key, 255, 255).is_ok());
+    }
+
+    #[test]
+    fn test_serde() {
+        let key = SymmetricKey::gen_key().unwrap();
+        let share = KeyShare::split(&key, 2, 2).unwrap().remove(0);
+        let ser = bincode::serialize(&share).unwrap();
+        let deser: KeyShare = bincode::deserialize(&ser).unwrap();
+        assert_eq!(deser.key_bytes(), share.key_bytes());
+    }
+}
@@ -1,7 +1,5 @@
 use std::fmt::Write;
 
-use anyhow::{Context, Error, Result};
-
 pub trait KeyMaterial {
     const HEADER: &'static str;
 
@@ -16,6 +14,89 @@ pub trait KeyMaterial {
     fn append_serialized(&self, v: &mut String) {
         append_serialized(v, &Self::HEADER, &self.key_bytes());
     }
+
+    fn serialize_with(&self, encoding: KeyEncoding) -> String {
+        match encoding {
+            KeyEncoding::Crc16 => self.serialize_to_string(),
+            KeyEncoding::Bch => serialize_bch(Self::HEADER, &self.key_bytes()),
+        }
+    }
+
+    /// Short, stable identifier for the key, safe to print. It does not
+    /// depend on how the key string is encoded.
+    fn fingerprint(&self) -> String {
+        fingerprint(Self::HEADER, &self.key_bytes())
+    }
+
+    /// The key as a list of words, for paper backups.
+    fn to_mnemonic(&self) -> anyhow::Result<String> {
+        crate::mnemonic::encode(Self::HEADER, &self.key_bytes())
+    }
+}
+
+/// Key types that `SymmetricKey::derive_key` can produce.
+pub trait DerivableKey: Sized {
+    /// KDF context of the final derivation step, so that keys of different
+    /// types derived from the same path are unrelated. At most 8 bytes.
+    const KDF_CONTEXT: &'static str;
+
+    /// How many bytes of key material `from_derived` takes.
+    const DERIVED_LEN: usize;
+
+    fn from_derived(data: &[u8]) -> anyhow::Result<Self>;
+}
+
+/// How the payload and checksum of a key string are written. Parsers accept
+/// either.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyEncoding {
+    /// `eseb0::sym::<base64>::<crc16>`. Readable by every version of eseb.
+    Crc16,
+    /// `eseb2::sym::<groups>`, in the bech32 alphabet with a BCH checksum per
+    /// group. Detects any 4 wrong characters and points at single typos, for
+    /// keys that are read aloud or typed in from paper.
+    Bch,
+}
+
+pub const BCH_VERSION: &str = "eseb2";
+
+/// The header used for `header`'s key type in the `Bch` encoding.
+pub fn bch_header(header: &str) -> String {
+    format!("{}::{}::", BCH_VERSION, header_type(header))
+}
+
+pub fn serialize_bch(header: &str, key: &[u8]) -> String {
+    let mut v = bch_header(header);
+    v.push_str(&crate::bch::encode(&v, key));
+    v
+}
+
+/// Whether `data` starts with `header` in either encoding.
+pub fn has_header(data: &str, header: &str) -> bool {
+    data.starts_with(header) || data.starts_with(&bch_header(header))
+}
+
+pub fn fingerprint(header: &str, key: &[u8]) -> String {
+    let mut state = sodiumoxide::crypto::generichash::State::new(Some(32), None)
+        .expect("valid blake2b parameters");
+    state.update(header.as_bytes()).expect("hash key header");
+    state.update(key).expect("hash key");
+    let digest = state.finalize().expect("finalize blake2b");
+    let mut v = String::default();
+    for byte in &digest.as_ref()[..8] {
+        write!(v, "{:02x}", byte).expect("error writing to string");
+    }
+    v
+}
+
+/// The key type part of a header, e.g. `sym` for `eseb0::sym::`.
+pub fn header_type(header: &str) -> &str {
+    header.split("::").nth(1).unwrap_or_default()
+}
+
+/// The version part of a header, e.g. `eseb0` for `eseb0::sym::`.
+pub fn header_version(header: &str) -> &str {
+    header.split("::").next().unwrap_or_default()
 }
 
 pub fn append_serialized(v: &mut String, header: &str, key: &[u8]) {
@@ -30,33 +111,435 @@ pub fn crc_encode(buf: &mut String, start: usize) {
     write!(buf, "::{:#05}", crc).expect("error writing to string");
 }
 
-pub fn crc_decode<'a>(buf: &'a str, header: &str) -> Result<Vec<u8>> {
-    if buf.len() < 7 || buf[buf.len() - 7..buf.len() - 5] != *"::" {
-        return Err(Error::msg(format!(
-            "expected ::xxxxx trailing 5 digit crc16"
-        )));
+/// Why a key string failed to parse. Key parsers return this inside an
+/// `anyhow::Error`, so use `downcast_ref::<KeyParseError>()` to inspect it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeyParseError {
+    /// The string does not start with the header for this key type.
+    WrongHeader {
+        expected: String,
+    },
+    /// The header names this key type, but a version we cannot read.
+    UnsupportedVersion {
+        expected: String,
+        found: String,
+    },
+    /// There is no `::xxxxx` trailing crc16.
+    MissingCrc,
+    /// `expected` is the crc16 written in the key, `actual` is what the rest
+    /// of the key hashes to.
+    BadCrc {
+        expected: u16,
+        actual: u16,
+    },
+    /// `position` is the byte offset into the key string.
+    BadBase64 {
+        position: usize,
+    },
+    /// A character outside the bech32 alphabet in a `Bch` encoded key.
+    InvalidCharacter {
+        position: usize,
+    },
+    /// The BCH checksum of a `Bch` encoded key does not match. `positions`
+    /// are byte offsets of the characters most likely to be wrong, if a
+    /// single typo or swap explains the error.
+    BadChecksum {
+        positions: Vec<usize>,
+    },
+    WrongLength {
+        expected: usize,
+        actual: usize,
+    },
+    /// A word of a mnemonic is not in the wordlist. `word` counts from 1, as
+    /// written on paper.
+    UnknownWord {
+        word: usize,
+    },
+    /// The checksum is fine but the payload is not a valid key of this type.
+    InvalidPayload(String),
+}
+
+impl std::fmt::Display for KeyParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            KeyParseError::WrongHeader { expected } => {
+                write!(f, "key does not start with header {}", expected)
+            }
+            KeyParseError::UnsupportedVersion { expected, found } => {
+                write!(
+                    f,
+                    "unsupported key version {}, expected {}",
+                    found, expected
+                )
+            }
+            KeyParseError::MissingCrc => write!(f, "expected ::xxxxx trailing 5 digit crc16"),
+            KeyParseError::BadCrc { expected, actual } => {
+                write!(f, "expected crc16 {} calculated {}", expected, actual)
+            }
+            KeyParseError::BadBase64 { position } => {
+                write!(f, "invalid base64 at position {}", position)
+            }
+            KeyParseError::InvalidCharacter { position } => {
+                write!(f, "invalid character at position {}", position)
+            }
+            KeyParseError::BadChecksum { positions } if positions.is_empty() => {
+                write!(f, "bad checksum")
+            }
+            KeyParseError::BadChecksum { positions } => {
+                write!(f, "bad checksum, check the characters at positions ")?;
+                for (i, position) in positions.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", position)?;
+                }
+                Ok(())
+            }
+            KeyParseError::WrongLength { expected, actual } => {
+                write!(f, "expected {} bytes of key data, got {}", expected, actual)
+            }
+            KeyParseError::UnknownWord { word } => write!(f, "unknown word {}", word),
+            KeyParseError::InvalidPayload(reason) => write!(f, "invalid key data: {}", reason),
+        }
     }
+}
+
+impl std::error::Error for KeyParseError {}
 
-    let msg_crc16: u16 = buf[buf.len() - 5..].parse().context("parse crc16")?;
+pub fn check_length(data: &[u8], expected: usize) -> Result<(), KeyParseError> {
+    if data.len() == expected {
+        Ok(())
+    } else {
+        Err(KeyParseError::WrongLength {
+            expected,
+            actual: data.len(),
+        })
+    }
+}
+
+// Works on bytes rather than `str` indices so that arbitrary (including
+// non-ASCII) input cannot panic.
+pub fn crc_decode(buf: &str, header: &str) -> Result<Vec<u8>, KeyParseError> {
+    let buf = buf.as_bytes();
+    if buf.len() < header.len() + 7 || buf[buf.len() - 7..buf.len() - 5] != *b"::" {
+        return Err(KeyParseError::MissingCrc);
+    }
+
+    let digits = &buf[buf.len() - 5..];
+    if !digits.iter().all(u8::is_ascii_digit) {
+        return Err(KeyParseError::MissingCrc);
+    }
+    let msg_crc16 = digits
+        .iter()
+        .fold(0u32, |acc, digit| acc * 10 + (digit - b'0') as u32);
     let data = &buf[..buf.len() - 7];
-    let comp_crc = crc16::State::<crc16::ARC>::calculate(data.as_bytes());
-    if msg_crc16 != comp_crc {
-        return Err(Error::msg(format!(
-            "expected crc16 {} calculated {}",
-            msg_crc16, comp_crc
-        )));
+    let comp_crc = crc16::State::<crc16::ARC>::calculate(data);
+    if msg_crc16 != comp_crc as u32 {
+        return Err(KeyParseError::BadCrc {
+            expected: msg_crc16
+                .try_into()
+                .map_err(|_| KeyParseError::MissingCrc)?,
+            actual: comp_crc,
+        });
     }
 
-    Ok(base64::decode(&data.as_bytes()[header.len()..]).context("decode bas64")?)
+    base64::decode(&data[header.len()..]).map_err(|e| {
+        let offset = match e {
+            base64::DecodeError::InvalidByte(offset, _) => offset,
+            base64::DecodeError::InvalidLastSymbol(offset, _) => offset,
+            base64::DecodeError::InvalidLength => data.len() - header.len(),
+        };
+        KeyParseError::BadBase64 {
+            position: header.len() + offset,
+        }
+    })
 }
 
-pub fn parse_header<'a>(data: &'a str, header: &str) -> Result<Vec<u8>> {
+pub fn parse_header(data: &str, header: &str) -> Result<Vec<u8>, KeyParseError> {
+    let bch_header = bch_header(header);
     if data.starts_with(header) {
         crc_decode(data, header)
+    } else if let Some(groups) = data.strip_prefix(&bch_header) {
+        crate::bch::decode(&bch_header, groups, bch_header.len())
     } else {
-        return Err(Error::msg(format!(
-            "key does not start with header {}",
-            &header
-        )));
+        // Same key type under another version, e.g. `eseb9::sym::`.
+        let version = header_version(header);
+        if let Some((found, rest)) = data.split_once("::") {
+            if found != version
+                && found.starts_with("eseb")
+                && rest.starts_with(&header[version.len() + 2..])
+            {
+                return Err(KeyParseError::UnsupportedVersion {
+                    expected: version.to_string(),
+                    found: found.to_string(),
+                });
+            }
+        }
+
+        Err(KeyParseError::WrongHeader {
+            expected: header.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+
+    use crate::{
+        AnyKey, BlindIndexKey, DeterministicAeadKey, DeterministicEncryptionSymmetricKey256,
+        FormatPreservingKey, KeyShare, OpaqueKey, SnowKeyPair, SnowPrivateKey, SnowPsk,
+        SnowPublicKey, SymmetricKey, WideBlockEncryptionKey256, WrappedKey,
+    };
+
+    fn parse_error<K>(data: &str) -> KeyParseError
+    where
+        K: std::str::FromStr<Err = anyhow::Error>,
+    {
+        match data.parse::<K>() {
+            Ok(_) => panic!("{} parsed", data),
+            Err(e) => e
+                .downcast_ref::<KeyParseError>()
+                .unwrap_or_else(|| panic!("unstructured error {:#}", e))
+                .clone(),
+        }
+    }
+
+    fn with_crc(header: &str, key: &[u8]) -> String {
+        let mut v = String::default();
+        append_serialized(&mut v, header, key);
+        v
+    }
+
+    #[test]
+    fn test_wrong_header() {
+        let key = SymmetricKey::gen_key().unwrap().serialize_to_string();
+        assert_eq!(
+            parse_error::<SnowPsk>(&key),
+            KeyParseError::WrongHeader {
+                expected: SnowPsk::HEADER.to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let key = SymmetricKey::gen_key().unwrap().serialize_to_string();
+        assert_eq!(
+            parse_error::<SymmetricKey>(&key.replacen("eseb0", "eseb7", 1)),
+            KeyParseError::UnsupportedVersion {
+                expected: "eseb0".to_string(),
+                found: "eseb7".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_bad_crc() {
+        let key = SymmetricKey::gen_key().unwrap().serialize_to_string();
+        let (data, crc) = key.rsplit_once("::").unwrap();
+        let crc: u16 = crc.parse().unwrap();
+        let bad = format!("{}::{:05}", data, crc.wrapping_add(1));
+        assert_eq!(
+            parse_error::<SymmetricKey>(&bad),
+            KeyParseError::BadCrc {
+                expected: crc.wrapping_add(1),
+                actual: crc,
+            }
+        );
+
+        assert_eq!(
+            parse_error::<SymmetricKey>(&format!("{}::99999", data)),
+            KeyParseError::MissingCrc
+        );
+        assert_eq!(parse_error::<SymmetricKey>(data), KeyParseError::MissingCrc);
+    }
+
+    #[test]
+    fn test_bad_base64() {
+        let key = with_crc(SymmetricKey::HEADER, &[7; 32]);
+        let bad = key.replacen('B', "!", 1);
+        let position = bad.find('!').unwrap();
+        let data = bad.rsplit_once("::").unwrap().0;
+        assert_eq!(
+            parse_error::<SymmetricKey>(&with_crc_raw(data)),
+            KeyParseError::BadBase64 { position }
+        );
+    }
+
+    // Recomputes the crc over data that is not valid base64.
+    fn with_crc_raw(data: &str) -> String {
+        let mut v = data.to_string();
+        crc_encode(&mut v, 0);
+        v
+    }
+
+    #[test]
+    fn test_wrong_length() {
+        assert_eq!(
+            parse_error::<SymmetricKey>(&with_crc(SymmetricKey::HEADER, &[1; 31])),
+            KeyParseError::WrongLength {
+                expected: 32,
+                actual: 31
+            }
+        );
+        assert_eq!(
+            parse_error::<SnowKeyPair>(&with_crc(SnowKeyPair::HEADER, &[1; 95])),
+            KeyParseError::WrongLength {
+                expected: 96,
+                actual: 95
+            }
+        );
+        assert_eq!(
+            parse_error::<SnowPublicKey>(&with_crc(SnowPublicKey::HEADER, &[1; 33])),
+            KeyParseError::WrongLength {
+                expected: 32,
+                actual: 33
+            }
+        );
+        assert_eq!(
+            parse_error::<SnowPrivateKey>(&with_crc(SnowPrivateKey::HEADER, &[])),
+            KeyParseError::WrongLength {
+                expected: 32,
+                actual: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_short_key_does_not_panic() {
+        // The crc separator overlaps the header.
+        assert_eq!(
+            parse_error::<SymmetricKey>("eseb0::sym::12345"),
+            KeyParseError::MissingCrc
+        );
+        assert_eq!(
+            parse_error::<SymmetricKey>("eseb0::sym::\u{e9}\u{e9}\u{e9}::1"),
+            KeyParseError::MissingCrc
+        );
+    }
+
+    #[test]
+    fn test_bch_encoding() {
+        let key = SnowKeyPair::gen_key().unwrap();
+        let bch = key.serialize_with(KeyEncoding::Bch);
+        assert!(bch.starts_with("eseb2::snow_key_pair::"));
+        assert_eq!(
+            bch.parse::<SnowKeyPair>().unwrap().key_bytes(),
+            key.key_bytes()
+        );
+        assert_eq!(
+            bch.parse::<SnowKeyPair>().unwrap().fingerprint(),
+            key.fingerprint()
+        );
+
+        // The crc16 form of eseb1 keys is still accepted.
+        let crc16 = key.serialize_to_string();
+        assert!(crc16.starts_with("eseb1::snow_key_pair::"));
+        assert_eq!(
+            crc16.parse::<SnowKeyPair>().unwrap().key_bytes(),
+            key.key_bytes()
+        );
+
+        let position = bch.len() - 10;
+        let mut typo = bch.clone().into_bytes();
+        typo[position] = if typo[position] == b'q' { b'p' } else { b'q' };
+        assert_eq!(
+            parse_error::<SnowKeyPair>(std::str::from_utf8(&typo).unwrap()),
+            KeyParseError::BadChecksum {
+                positions: vec![position]
+            }
+        );
+    }
+
+    macro_rules! parse_all {
+        ($data:expr, $($key:ty),*) => {
+            $(
+                if let Err(e) = $data.parse::<$key>() {
+                    prop_assert!(
+                        e.downcast_ref::<KeyParseError>().is_some(),
+                        "unstructured error {:#}",
+                        e
+                    );
+                }
+            )*
+        };
+    }
+
+    proptest! {
+        #[test]
+        fn test_arbitrary_strings(data in any::<String>()) {
+            parse_all!(
+                data,
+                SymmetricKey,
+                DeterministicEncryptionSymmetricKey256,
+                DeterministicAeadKey,
+                WideBlockEncryptionKey256,
+                FormatPreservingKey,
+                BlindIndexKey,
+                OpaqueKey,
+                SnowKeyPair,
+                SnowPublicKey,
+                SnowPrivateKey,
+                SnowPsk,
+                WrappedKey,
+                KeyShare
+            );
+            let _ = data.parse::<AnyKey>();
+        }
+
+        #[test]
+        fn test_arbitrary_key_strings(
+            header in prop::sample::select(AnyKey::HEADERS),
+            bch in any::<bool>(),
+            body in "[A-Za-z0-9+/=:!\u{e9}-]{0,80}",
+            crc in "(::[0-9]{0,6})?",
+        ) {
+            let header = if bch { bch_header(header) } else { header.to_string() };
+            let data = format!("{}{}{}", header, body, crc);
+            parse_all!(
+                data,
+                SymmetricKey,
+                DeterministicEncryptionSymmetricKey256,
+                DeterministicAeadKey,
+                WideBlockEncryptionKey256,
+                FormatPreservingKey,
+                BlindIndexKey,
+                OpaqueKey,
+                SnowKeyPair,
+                SnowPublicKey,
+                SnowPrivateKey,
+                SnowPsk,
+                WrappedKey,
+                KeyShare
+            );
+        }
+
+        // Valid checksums, so the payload checks are what is being tested.
+        #[test]
+        fn test_arbitrary_payloads(
+            header in prop::sample::select(AnyKey::HEADERS),
+            payload in prop::collection::vec(any::<u8>(), 0..200),
+        ) {
+            let data = with_crc(header, &payload);
+            parse_all!(
+                data,
+                SymmetricKey,
+                DeterministicEncryptionSymmetricKey256,
+                DeterministicAeadKey,
+                WideBlockEncryptionKey256,
+                FormatPreservingKey,
+                BlindIndexKey,
+                OpaqueKey,
+                SnowKeyPair,
+                SnowPublicKey,
+                SnowPrivateKey,
+                SnowPsk,
+                WrappedKey,
+                KeyShare
+            );
+        }
     }
 }
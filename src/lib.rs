@@ -1,24 +1,63 @@
+mod any_key;
+mod archive;
+mod atomic_file;
+mod bch;
+mod blind_index;
 mod deterministic;
+mod dotenv;
+mod encrypted_field;
 mod encrypted_record_writer;
 mod encrypting_writer;
+mod format_preserving;
+mod inspect;
+mod key_share;
 mod key_util;
+mod mnemonic;
 mod opaque_key;
+mod passphrase;
 mod serde_support;
+mod siv;
 mod snow;
+mod structured;
 mod symmetric_key;
+mod wide_block;
+mod wrapped_key;
 
+pub use crate::any_key::AnyKey;
+pub use crate::archive::{ArchiveEntry, ArchiveReader, ArchiveWriter};
+pub use crate::atomic_file::AtomicFile;
+pub use crate::blind_index::BlindIndexKey;
 pub use crate::deterministic::DeterministicEncryptionSymmetricKey256;
+pub use crate::dotenv::parse_dotenv;
+pub use crate::encrypted_field::{with_field_key, Encrypted};
 pub use crate::encrypted_record_writer::{
-    DecryptingRecordReader, DecryptingRecordWriter, EncryptingRecordWriter,
+    DecryptingRecordReader, DecryptingRecordWriter, EncryptingRecordWriter, StreamError,
 };
 pub use crate::encrypting_writer::{DecryptingReader, EncryptingWriter};
-pub use crate::key_util::KeyMaterial;
+pub use crate::format_preserving::{FormatPreservingKey, ALPHANUMERIC, DIGITS};
+pub use crate::inspect::{inspect_file, Bucket, FileInfo, PassphraseInfo, RecordSizes};
+pub use crate::key_share::KeyShare;
+pub use crate::key_util::{
+    has_header, header_type, header_version, DerivableKey, KeyEncoding, KeyMaterial, KeyParseError,
+    BCH_VERSION,
+};
 pub use crate::opaque_key::OpaqueKey;
-pub use crate::snow::{SnowKeyPair, SnowPsk, SnowPublicKey};
+pub use crate::passphrase::PassphraseParams;
+pub use crate::siv::DeterministicAeadKey;
+pub use crate::snow::{SnowKeyPair, SnowPrivateKey, SnowPsk, SnowPublicKey};
+pub use crate::structured::{
+    structured_decrypt, structured_decrypt_value, structured_encrypt, structured_encrypt_value,
+    StructuredFormat,
+};
 pub use crate::symmetric_key::SymmetricKey;
+pub use crate::wide_block::WideBlockEncryptionKey256;
+pub use crate::wrapped_key::WrappedKey;
+
+use std::io::Read;
 
 use anyhow::{Context, Result};
 use record_reader::{Format, RecordReader, RecordWriter};
+use sodiumoxide::crypto::secretstream;
 
 /// `format` is the format of the underlying file. You almost certainly want
 /// `Record` or `Record32`.
@@ -28,11 +67,238 @@ pub fn symmetric_decrypt_verify_file<I: std::io::Read, O: std::io::Write>(
     writer: O,
     compress: bool,
     format: Format,
+) -> Result<()> {
+    let mut reader = record_reader::IoRecordReader::from_read(reader, format, std::usize::MAX);
+    decrypt_verify_records(key, &mut reader, writer, compress)
+}
+
+/// `format` is the format of the underlying file. You almost certainly want
+/// `Record` or `Record32`.
+pub fn symmetric_encrypt_sign_file<I: std::io::BufRead, O: std::io::Write>(
+    key: &SymmetricKey,
+    reader: I,
+    writer: O,
+    compress: bool,
+    format: Format,
+) -> Result<()> {
+    let writer = record_reader::IoRecordWriter::new(writer, format);
+    encrypt_sign_records(key, reader, writer, compress)
+}
+
+/// Re-encrypts a file made by `symmetric_encrypt_sign_file` under `to_key`,
+/// without the cleartext leaving memory. Record boundaries are kept, and
+/// compression and format may change along the way.
+///
+/// Output is written as the input is verified, so on error the caller must
+/// discard whatever was written; `AtomicFile` does this.
+#[allow(clippy::too_many_arguments)]
+pub fn symmetric_rekey_file<I: std::io::Read, O: std::io::Write>(
+    from_key: &SymmetricKey,
+    to_key: &SymmetricKey,
+    reader: I,
+    writer: O,
+    from_compress: bool,
+    to_compress: bool,
+    from_format: Format,
+    to_format: Format,
+) -> Result<()> {
+    // Compressed records pass through untouched if both sides compress.
+    let reader = record_reader::IoRecordReader::from_read(reader, from_format, std::usize::MAX);
+    let mut decrypter =
+        DecryptingRecordReader::new(reader, from_key.clone(), from_compress && !to_compress)?;
+    let writer = record_reader::IoRecordWriter::new(writer, to_format);
+    let mut encrypter =
+        EncryptingRecordWriter::new(writer, to_key.clone(), to_compress && !from_compress)?;
+
+    while let Some(rec) = decrypter
+        .maybe_read_record()
+        .context("decrypt and verify record")?
+    {
+        encrypter
+            .write_record(rec)
+            .context("encrypt and write record")?;
+    }
+
+    if decrypter
+        .into_inner()
+        .maybe_read_record()
+        .context("read record")?
+        .is_some()
+    {
+        return Err(StreamError::TrailingData.into());
+    }
+
+    encrypter.into_inner()?.into_inner().flush()?;
+
+    Ok(())
+}
+
+/// Checks a file made by `symmetric_encrypt_sign_file` as decrypting it
+/// would: every record must authenticate, the stream must end with its Final
+/// tag, and nothing may follow it. The cleartext is thrown away as it is
+/// read, so this runs in constant memory; records longer than
+/// `MAX_RECORD_LEN` fail with `StreamError::RecordTooLarge` before they are
+/// read.
+///
+/// Failures carry a `StreamError` where one applies.
+pub fn symmetric_verify_file<I: std::io::Read>(
+    key: &SymmetricKey,
+    reader: I,
+    compress: bool,
+    format: Format,
+) -> Result<()> {
+    let reader = BoundedRecordReader::new(reader, format);
+    let mut decrypter = DecryptingRecordReader::new(reader, key.clone(), compress)?;
+    while decrypter
+        .maybe_read_record()
+        .context("decrypt and verify record")?
+        .is_some()
+    {}
+
+    if decrypter
+        .into_inner()
+        .maybe_read_record()
+        .context("read record")?
+        .is_some()
+    {
+        return Err(StreamError::TrailingData.into());
+    }
+
+    Ok(())
+}
+
+// The most cleartext `symmetric_encrypt_sign_file` puts in one record.
+const CHUNK_LEN: usize = 1 << 16;
+
+/// The longest record `symmetric_encrypt_sign_file` writes: a full chunk,
+/// grown a little if it does not compress, plus the secretstream tag.
+pub const MAX_RECORD_LEN: usize = CHUNK_LEN + CHUNK_LEN / 64 + secretstream::ABYTES;
+
+// Reads records of at most `MAX_RECORD_LEN` bytes, for files that may not be
+// trusted. The record reader fails with an `io::Error` when the input does,
+// and without one only when a length prefix is over the limit, so those
+// failures carry `StreamError::RecordTooLarge`.
+pub(crate) struct BoundedRecordReader<R: Read>(record_reader::IoRecordReader<R>);
+
+impl<R: Read> BoundedRecordReader<R> {
+    pub(crate) fn new(reader: R, format: Format) -> BoundedRecordReader<R> {
+        BoundedRecordReader(record_reader::IoRecordReader::from_read(
+            reader,
+            format,
+            MAX_RECORD_LEN,
+        ))
+    }
+}
+
+impl<R: Read> RecordReader for BoundedRecordReader<R> {
+    fn maybe_read_record(&mut self) -> Result<Option<&[u8]>> {
+        self.0.maybe_read_record().map_err(|e| {
+            if e.chain().any(|e| e.is::<std::io::Error>()) {
+                e
+            } else {
+                e.context(StreamError::RecordTooLarge)
+            }
+        })
+    }
+}
+
+// Enough for the first record in any format, even a passphrase header.
+const DETECT_LEN: usize = 64;
+
+/// The whole file again, after `detect_format` has looked at its start.
+pub type DetectedReader<R> = std::io::Chain<std::io::Cursor<Vec<u8>>, R>;
+
+/// Works out whether an encrypted file uses the `Record` or `Record32`
+/// framing, by checking that its first record parses as a stream header (or
+/// passphrase header) in that framing. Only the first few bytes are read;
+/// the returned reader still yields the whole file.
+///
+/// Returns `None` for the format if neither matches.
+pub fn detect_format<R: std::io::Read>(
+    mut reader: R,
+) -> Result<(Option<Format>, DetectedReader<R>)> {
+    let mut prefix = Vec::with_capacity(DETECT_LEN);
+    (&mut reader)
+        .take(DETECT_LEN as u64)
+        .read_to_end(&mut prefix)
+        .context("read start of file")?;
+
+    let format = [Format::Record, Format::Record32]
+        .into_iter()
+        .find(|format| {
+            let mut records =
+                record_reader::BufferRecordReader::new((&prefix[..]).into(), *format, DETECT_LEN);
+            matches!(
+                records.maybe_read_record(),
+                Ok(Some(rec)) if rec.len() == secretstream::HEADERBYTES
+                    || PassphraseParams::is_header(rec)
+            )
+        });
+
+    Ok((format, std::io::Cursor::new(prefix).chain(reader)))
+}
+
+/// Rewrites the framing of an encrypted file, e.g. from the legacy `Record32`
+/// to `Record`, without decrypting it. The records themselves are copied
+/// unchanged, so this needs no key, but nor does it verify anything.
+pub fn reframe_file<I: std::io::Read, O: std::io::Write>(
+    reader: I,
+    writer: O,
+    from_format: Format,
+    to_format: Format,
+) -> Result<()> {
+    let mut reader = record_reader::IoRecordReader::from_read(reader, from_format, std::usize::MAX);
+    let mut writer = record_reader::IoRecordWriter::new(writer, to_format);
+    while let Some(rec) = reader.maybe_read_record().context("read record")? {
+        writer.write_record(rec).context("write record")?;
+    }
+    writer.into_inner().flush()?;
+    Ok(())
+}
+
+/// Like `symmetric_decrypt_verify_file`, but the key is derived from
+/// `passphrase` using the Argon2id parameters stored at the start of the file.
+pub fn passphrase_decrypt_verify_file<I: std::io::Read, O: std::io::Write>(
+    passphrase: &[u8],
+    reader: I,
+    writer: O,
+    compress: bool,
+    format: Format,
+) -> Result<()> {
+    let mut reader = record_reader::IoRecordReader::from_read(reader, format, std::usize::MAX);
+    let params = PassphraseParams::from_bytes(reader.read_record().context("read header")?)?;
+    let key = params.derive_key(passphrase)?;
+    decrypt_verify_records(&key, &mut reader, writer, compress)
+}
+
+/// Like `symmetric_encrypt_sign_file`, but the key is derived from
+/// `passphrase` with Argon2id. `params` (including the salt) is written to the
+/// start of the file so that decryption only needs the passphrase.
+pub fn passphrase_encrypt_sign_file<I: std::io::BufRead, O: std::io::Write>(
+    passphrase: &[u8],
+    params: &PassphraseParams,
+    reader: I,
+    writer: O,
+    compress: bool,
+    format: Format,
+) -> Result<()> {
+    let key = params.derive_key(passphrase)?;
+    let mut writer = record_reader::IoRecordWriter::new(writer, format);
+    writer
+        .write_record(&params.to_bytes())
+        .context("write passphrase header")?;
+    encrypt_sign_records(&key, reader, writer, compress)
+}
+
+fn decrypt_verify_records<R: RecordReader, O: std::io::Write>(
+    key: &SymmetricKey,
+    reader: &mut R,
+    writer: O,
+    compress: bool,
 ) -> Result<()> {
     let writer = record_reader::IoRecordWriter::new(writer, Format::Chunk);
     let mut decrypter =
         encrypted_record_writer::DecryptingRecordWriter::new(writer, key.clone(), compress)?;
-    let mut reader = record_reader::IoRecordReader::from_read(reader, format, std::usize::MAX);
 
     while let Some(rec) = reader.maybe_read_record().context("read record")? {
         decrypter
@@ -45,28 +311,26 @@ pub fn symmetric_decrypt_verify_file<I: std::io::Read, O: std::io::Write>(
     Ok(())
 }
 
-/// `format` is the format of the underlying file. You almost certainly want
-/// `Record` or `Record32`.
-pub fn symmetric_encrypt_sign_file<I: std::io::BufRead, O: std::io::Write>(
+fn encrypt_sign_records<I: std::io::BufRead, W: RecordWriter>(
     key: &SymmetricKey,
     reader: I,
-    writer: O,
+    writer: W,
     compress: bool,
-    format: Format,
 ) -> Result<()> {
-    let writer = record_reader::IoRecordWriter::new(writer, format);
     let mut encrypter =
         encrypted_record_writer::EncryptingRecordWriter::new(writer, key.clone(), compress)?;
-    let mut reader =
-        record_reader::IoRecordReader::from_read(reader, Format::Chunk, std::usize::MAX);
+    let mut reader = record_reader::IoRecordReader::from_read(reader, Format::Chunk, CHUNK_LEN);
 
     while let Some(rec) = reader.maybe_read_record().context("read record")? {
-        encrypter
-            .write_record(&rec)
-            .context("encrypt and write record")?;
+        // Readers refuse records over `MAX_RECORD_LEN`.
+        for chunk in rec.chunks(CHUNK_LEN) {
+            encrypter
+                .write_record(chunk)
+                .context("encrypt and write record")?;
+        }
     }
 
-    encrypter.into_inner()?.into_inner().flush()?;
+    encrypter.into_inner()?.flush()?;
 
     Ok(())
 }
@@ -150,4 +414,232 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_passphrase_encryption() {
+        let params = PassphraseParams::interactive().unwrap();
+        let cleartext = b"my cool text is here";
+        let mut ciphertext = Vec::default();
+        passphrase_encrypt_sign_file(
+            b"hunter2",
+            &params,
+            &cleartext[..],
+            &mut ciphertext,
+            /*compress=*/ false,
+            Format::Record,
+        )
+        .unwrap();
+
+        let mut decrypted = Vec::default();
+        passphrase_decrypt_verify_file(
+            b"hunter2",
+            &*ciphertext,
+            &mut decrypted,
+            /*compress=*/ false,
+            Format::Record,
+        )
+        .unwrap();
+        assert_eq!(decrypted, cleartext);
+
+        assert!(passphrase_decrypt_verify_file(
+            b"hunter3",
+            &*ciphertext,
+            &mut Vec::default(),
+            /*compress=*/ false,
+            Format::Record,
+        )
+        .is_err());
+
+        // The salt is not covered by any MAC of its own, but changing it
+        // changes the key.
+        let salt_pos = find_subslice(&ciphertext, params.salt()).unwrap();
+        let mut ciphertext_bad = ciphertext.clone();
+        ciphertext_bad[salt_pos] ^= 1;
+        assert!(passphrase_decrypt_verify_file(
+            b"hunter2",
+            &*ciphertext_bad,
+            &mut Vec::default(),
+            /*compress=*/ false,
+            Format::Record,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_truncated_file() {
+        let key = SymmetricKey::gen_key().unwrap();
+        let ciphertext =
+            symmetric_encrypt_sign(&key, b"some text", false, Format::Record32).unwrap();
+        let last = ciphertext.len() - 4 - secretstream::ABYTES;
+        assert!(
+            symmetric_decrypt_verify(&key, &ciphertext[..last], false, Format::Record32).is_err()
+        );
+        assert!(symmetric_decrypt_verify(&key, &[], false, Format::Record32).is_err());
+    }
+
+    #[test]
+    fn test_rekey() {
+        let key1 = SymmetricKey::gen_key().unwrap();
+        let key2 = SymmetricKey::gen_key().unwrap();
+        let mut writer = EncryptingRecordWriter::new(
+            record_reader::BufferRecordWriter::new(Format::Record32),
+            key1.clone(),
+            /*compress=*/ false,
+        )
+        .unwrap();
+        for rec in [&b"pumpkins"[..], b"", b"scream"] {
+            writer.write_record(rec).unwrap();
+        }
+        let ciphertext = writer.into_inner().unwrap().into_cow();
+
+        for (compress, format) in [(false, Format::Record32), (true, Format::Record)] {
+            let mut rekeyed = Vec::default();
+            symmetric_rekey_file(
+                &key1,
+                &key2,
+                &*ciphertext,
+                &mut rekeyed,
+                /*from_compress=*/ false,
+                compress,
+                Format::Record32,
+                format,
+            )
+            .unwrap();
+
+            assert!(symmetric_decrypt_verify(&key1, &rekeyed, compress, format).is_err());
+            let reader = record_reader::IoRecordReader::from_read(&*rekeyed, format, usize::MAX);
+            let mut reader = DecryptingRecordReader::new(reader, key2.clone(), compress).unwrap();
+            assert_eq!(reader.read_record().unwrap(), b"pumpkins");
+            assert_eq!(reader.read_record().unwrap(), b"");
+            assert_eq!(reader.read_record().unwrap(), b"scream");
+            assert!(reader.maybe_read_record().unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_rekey_refuses_bad_input() {
+        let key1 = SymmetricKey::gen_key().unwrap();
+        let key2 = SymmetricKey::gen_key().unwrap();
+        let ciphertext =
+            symmetric_encrypt_sign(&key1, b"some text", false, Format::Record32).unwrap();
+        let rekey = |key: &SymmetricKey, ciphertext: &[u8]| {
+            symmetric_rekey_file(
+                key,
+                &key2,
+                ciphertext,
+                Vec::default(),
+                false,
+                false,
+                Format::Record32,
+                Format::Record32,
+            )
+        };
+
+        assert!(rekey(&key1, &ciphertext).is_ok());
+        assert!(rekey(&key2, &ciphertext).is_err());
+
+        let mut trailing = ciphertext.clone();
+        trailing.extend_from_slice(&ciphertext);
+        assert!(rekey(&key1, &trailing).is_err());
+
+        // Cut off the final record: a 4 byte length and an empty message with
+        // the Final tag.
+        let last = ciphertext.len() - 4 - secretstream::ABYTES;
+        assert!(rekey(&key1, &ciphertext[..last]).is_err());
+    }
+
+    #[test]
+    fn test_verify() {
+        let key1 = SymmetricKey::gen_key().unwrap();
+        let key2 = SymmetricKey::gen_key().unwrap();
+        let ciphertext =
+            symmetric_encrypt_sign(&key1, b"some text", true, Format::Record32).unwrap();
+        let class = |key: &SymmetricKey, ciphertext: &[u8]| {
+            symmetric_verify_file(key, ciphertext, true, Format::Record32)
+                .unwrap_err()
+                .downcast_ref::<StreamError>()
+                .copied()
+        };
+
+        assert!(symmetric_verify_file(&key1, &*ciphertext, true, Format::Record32).is_ok());
+        assert_eq!(class(&key2, &ciphertext), Some(StreamError::Authentication));
+
+        let mut corrupt = ciphertext.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert_eq!(class(&key1, &corrupt), Some(StreamError::Authentication));
+
+        let last = ciphertext.len() - 4 - secretstream::ABYTES;
+        assert_eq!(
+            class(&key1, &ciphertext[..last]),
+            Some(StreamError::Truncated)
+        );
+        assert_eq!(class(&key1, &[]), Some(StreamError::Truncated));
+
+        let mut trailing = ciphertext.clone();
+        trailing.extend_from_slice(&ciphertext);
+        assert_eq!(class(&key1, &trailing), Some(StreamError::TrailingData));
+
+        // The header record, then a length prefix of 4 GiB with nothing after
+        // it. Reading it would fail as truncated only after allocating.
+        let mut huge = ciphertext[..4 + secretstream::HEADERBYTES].to_vec();
+        huge.extend_from_slice(&[0xff; 4]);
+        assert_eq!(class(&key1, &huge), Some(StreamError::RecordTooLarge));
+    }
+
+    #[test]
+    fn test_detect_format() {
+        let key = SymmetricKey::gen_key().unwrap();
+        let params = PassphraseParams::interactive().unwrap();
+        for format in [Format::Record, Format::Record32] {
+            let ciphertext = symmetric_encrypt_sign(&key, b"some text", false, format).unwrap();
+            let (detected, mut reader) = detect_format(&*ciphertext).unwrap();
+            assert_eq!(detected, Some(format));
+            let mut all = Vec::default();
+            reader.read_to_end(&mut all).unwrap();
+            assert_eq!(all, ciphertext);
+
+            let mut ciphertext = Vec::default();
+            passphrase_encrypt_sign_file(
+                b"pw",
+                &params,
+                &b"text"[..],
+                &mut ciphertext,
+                false,
+                format,
+            )
+            .unwrap();
+            assert_eq!(detect_format(&*ciphertext).unwrap().0, Some(format));
+        }
+
+        assert_eq!(detect_format(&b""[..]).unwrap().0, None);
+        assert_eq!(
+            detect_format(&b"plain text, not encrypted"[..]).unwrap().0,
+            None
+        );
+    }
+
+    #[test]
+    fn test_reframe() {
+        let key = SymmetricKey::gen_key().unwrap();
+        let old = symmetric_encrypt_sign(&key, b"some text", true, Format::Record32).unwrap();
+        let mut new = Vec::default();
+        reframe_file(&*old, &mut new, Format::Record32, Format::Record).unwrap();
+        assert_eq!(detect_format(&*new).unwrap().0, Some(Format::Record));
+        assert_eq!(
+            symmetric_decrypt_verify(&key, &new, true, Format::Record).unwrap(),
+            b"some text"
+        );
+
+        assert!(reframe_file(
+            &old[..old.len() - 1],
+            Vec::default(),
+            Format::Record32,
+            Format::Record
+        )
+        .is_err());
+    }
+
+    fn find_subslice(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+        haystack.windows(needle.len()).position(|w| w == needle)
+    }
 }
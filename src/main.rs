@@ -1,7 +1,9 @@
 use eseb::*;
 
-use anyhow::{Error, Result};
-use clap::{App, ArgMatches};
+use std::io::{BufRead, Read, Write};
+
+use anyhow::{Context, Error, Result};
+use clap::{App, ArgGroup, ArgMatches};
 use record_reader::Format;
 
 include!(concat!(env!("OUT_DIR"), "/generated_stamp.rs"));
@@ -16,58 +18,762 @@ fn fmain() -> Result<()> {
         .about("Elixir's Simple Encoder Binary, a simple wrapper around NaCl to perform symmetric encryption and verification of files.")
         .version(format!("{} ({})", env!("CARGO_PKG_VERSION"), BUILD_STAMP.git_revision_cleanness()).as_ref())
         .author(env!("CARGO_PKG_AUTHORS"))
+        .arg(clap::Arg::from_usage("--key-passphrase-fd=[FD] 'Read the passphrase of a wrapped key from file descriptor FD instead of the TTY, one line per wrapped key.'").global(true))
         .subcommand(
             App::new("encrypt")
                 .about("Encrypt and sign")
-                .arg_from_usage("-e, --symmetric=<KEY> 'Symmetric encryption using key/keyfile.'")
+                .arg_from_usage("-e, --symmetric=[KEY] 'Symmetric encryption using key/keyfile.'")
+                .arg_from_usage("-p, --passphrase 'Derive the key from a passphrase read from the TTY.'")
+                .arg_from_usage("--passphrase-fd=[FD] 'Read the passphrase from file descriptor FD instead of the TTY.'")
+                .group(ArgGroup::new("key").args(&["symmetric", "passphrase", "passphrase-fd"]).required(true))
             .arg_from_usage("-l, --legacy 'Use legacy 32-bit record format.'")
-                .arg_from_usage("-c, --compress 'Compress'"),
+                .arg_from_usage("-c, --compress 'Compress'")
+                .arg_from_usage("-i, --input=[FILE] 'Read from FILE instead of stdin.'")
+                .arg_from_usage("-o, --output=[FILE] 'Write to FILE instead of stdout. It only appears once everything succeeded.'")
+                .arg(clap::Arg::from_usage("--in-place 'Replace the input file with the output.'")
+                     .requires("input")
+                     .conflicts_with("output")),
         )
         .subcommand(
             App::new("decrypt")
                 .about("Decrypt and verify")
                 .arg_from_usage("-c, --compress 'Decompress'")
-                .arg_from_usage("-l, --legacy 'Use legacy 32-bit record format.'")
-                .arg_from_usage("-e, --symmetric=<KEY> 'Symmetric decryption using key/keyfile.'"),
+                .arg_from_usage("-l, --legacy 'Force the legacy 32-bit record format. Detected automatically otherwise.'")
+                .arg_from_usage("-e, --symmetric=[KEY] 'Symmetric decryption using key/keyfile.'")
+                .arg_from_usage("-p, --passphrase 'Derive the key from a passphrase read from the TTY.'")
+                .arg_from_usage("--passphrase-fd=[FD] 'Read the passphrase from file descriptor FD instead of the TTY.'")
+                .group(ArgGroup::new("key").args(&["symmetric", "passphrase", "passphrase-fd"]).required(true))
+                .arg_from_usage("-i, --input=[FILE] 'Read from FILE instead of stdin.'")
+                .arg_from_usage("-o, --output=[FILE] 'Write to FILE instead of stdout. It only appears once everything succeeded.'")
+                .arg(clap::Arg::from_usage("--in-place 'Replace the input file with the output.'")
+                     .requires("input")
+                     .conflicts_with("output")),
+        )
+        .subcommand(det_app("det-encrypt", "Deterministically encrypt 32-byte fields, one per line"))
+        .subcommand(det_app("det-decrypt", "Decrypt fields from det-encrypt, one per line"))
+        .subcommand(
+            App::new("structured")
+                .about("Encrypt the values of a JSON, YAML or TOML document, leaving its keys readable")
+                .subcommand_required(true)
+                .subcommand(structured_app("encrypt", "Encrypt every value and add a MAC over the document"))
+                .subcommand(structured_app("decrypt", "Decrypt every value and check the MAC")),
+        )
+        .subcommand(
+            App::new("archive")
+                .about("Encrypted archives of many files, which can be listed and extracted one file at a time")
+                .subcommand_required(true)
+                .subcommand(
+                    App::new("create")
+                        .about("Archive files and directories")
+                        .arg_from_usage("-e, --symmetric=<KEY> 'Symmetric key/keyfile.'")
+                        .arg_from_usage("-c, --compress 'Compress each file.'")
+                        .arg_from_usage("-o, --output=<ARCHIVE> 'Archive to write.'")
+                        .arg_from_usage("<PATH>... 'Files and directories to archive, by relative path.'"),
+                )
+                .subcommand(
+                    App::new("list")
+                        .about("List the files in an archive, decrypting only its table of contents")
+                        .arg_from_usage("-e, --symmetric=<KEY> 'Symmetric key/keyfile.'")
+                        .arg_from_usage("--json 'Print JSON.'")
+                        .arg_from_usage("<ARCHIVE> 'Archive to list.'"),
+                )
+                .subcommand(
+                    App::new("extract")
+                        .about("Extract files from an archive")
+                        .arg_from_usage("-e, --symmetric=<KEY> 'Symmetric key/keyfile.'")
+                        .arg_from_usage("-C, --directory=[DIR] 'Extract into DIR instead of the current directory.'")
+                        .arg_from_usage("--keep-special-bits 'Keep setuid, setgid and sticky bits. Files are extracted with only their permission bits otherwise.'")
+                        .arg_from_usage("<ARCHIVE> 'Archive to extract from.'")
+                        .arg_from_usage("[PATH]... 'Files or directories to extract. Everything if omitted.'"),
+                ),
+        )
+        .subcommand(
+            App::new("edit")
+                .about("Decrypt a file into $EDITOR and re-encrypt it if it changed")
+                .arg_from_usage("-e, --symmetric=<KEY> 'Symmetric key/keyfile.'")
+                .arg_from_usage("-c, --compress 'The file is compressed. Compression cannot be detected, so the file is re-encrypted compressed exactly when this is given.'")
+                .arg_from_usage("-l, --legacy 'Force the legacy 32-bit record format. Detected automatically otherwise, and kept.'")
+                .arg_from_usage("<FILE> 'Encrypted file to edit.'"),
+        )
+        .subcommand(
+            App::new("exec")
+                .about("Run a command with the variables of an encrypted dotenv file in its environment")
+                .arg_from_usage("-e, --symmetric=<KEY> 'Symmetric key/keyfile.'")
+                .arg_from_usage("-c, --compress 'The file is compressed.'")
+                .arg_from_usage("-l, --legacy 'Force the legacy 32-bit record format. Detected automatically otherwise.'")
+                .arg_from_usage("<FILE> 'Encrypted dotenv file.'")
+                .arg(clap::Arg::from_usage("<COMMAND>... 'Command and arguments, after --.'")
+                     .last(true)),
         )
-        .subcommand(App::new("keygen").about("Generate symmetric key")
+        .subcommand(
+            App::new("rekey")
+                .about("Re-encrypt files under a new key, replacing them in place")
+                .arg_from_usage("--from=<KEY> 'Current key/keyfile.'")
+                .arg_from_usage("--to=<KEY> 'New key/keyfile.'")
+                .arg_from_usage("-c, --compress 'The files are compressed.'")
+                .arg_from_usage("-l, --legacy 'Force the legacy 32-bit record format for the input. Detected automatically otherwise.'")
+                .arg(clap::Arg::from_usage("--to-compress=[BOOL] 'Whether to compress the output. Defaults to the same as the input.'")
+                     .possible_values(["yes", "no"]))
+                .arg(clap::Arg::from_usage("--to-legacy=[BOOL] 'Whether to use the legacy 32-bit record format for the output. Defaults to the same as the input.'")
+                     .possible_values(["yes", "no"]))
+                .arg_from_usage("<FILE>... 'Files to re-encrypt.'"),
+        )
+        .subcommand(
+            App::new("verify")
+                .about("Check that files decrypt and authenticate, without writing the cleartext anywhere")
+                .arg_from_usage("-e, --symmetric=<KEY> 'Symmetric key/keyfile.'")
+                .arg_from_usage("-c, --compress 'The files are compressed.'")
+                .arg_from_usage("-l, --legacy 'Force the legacy 32-bit record format. Detected automatically otherwise.'")
+                .arg_from_usage("<FILE>... 'Files to verify.'"),
+        )
+        .subcommand(
+            App::new("inspect")
+                .about("Describe an encrypted file from its framing and headers, without a key")
+                .arg_from_usage("--json 'Print JSON.'")
+                .arg_from_usage("[FILE] 'File to inspect. Reads stdin if omitted.'"),
+        )
+        .subcommand(
+            App::new("migrate")
+                .about("Convert files from the legacy 32-bit record format to the current one, without decrypting them")
+                .arg_from_usage("<FILE>... 'Files to convert in place.'"),
+        )
+        .subcommand(
+            App::new("key")
+                .about("Manage key strings")
+                .subcommand_required(true)
+                .subcommand(
+                    App::new("wrap")
+                        .about("Encrypt a key under a passphrase")
+                        .arg_from_usage("<KEY> 'Key or keyfile to wrap.'")
+                        .arg_from_usage("--passphrase-fd=[FD] 'Read the passphrase from file descriptor FD instead of the TTY.'"),
+                )
+                .subcommand(
+                    App::new("unwrap")
+                        .about("Decrypt a wrapped key")
+                        .arg_from_usage("<KEY> 'Wrapped key or keyfile.'")
+                        .arg_from_usage("--passphrase-fd=[FD] 'Read the passphrase from file descriptor FD instead of the TTY.'"),
+                )
+                .subcommand(
+                    App::new("split")
+                        .about("Split a key into shares, any THRESHOLD of which rebuild it")
+                        .arg_from_usage("<KEY> 'Key or keyfile to split.'")
+                        .arg_from_usage("-k, --threshold=<THRESHOLD> 'Number of shares needed to rebuild the key.'")
+                        .arg_from_usage("-n, --shares=<SHARES> 'Number of shares to create.'"),
+                )
+                .subcommand(
+                    App::new("combine")
+                        .about("Rebuild a key from shares")
+                        .arg_from_usage("[SHARES]... 'Shares or files containing them. Read from stdin, one per line, if omitted.'"),
+                )
+                .subcommand(
+                    App::new("derive")
+                        .about("Derive a subkey from a master symmetric key")
+                        .arg_from_usage("<KEY> 'Master key or keyfile.'")
+                        .arg_from_usage("<PATH> 'Slash separated names, e.g. prod/billing/logs.'")
+                        .arg(clap::Arg::from_usage("-t, --type=[TYPE] 'Type of key to derive.'")
+                             .possible_values(["sym", "deterministic", "wide", "siv", "fpe", "blind-index", "psk"])
+                             .default_value("sym"))
+                        .arg_from_usage("--bch 'Use the typo-resistant BCH checksum, for keys read aloud or copied from paper.'"),
+                )
+                .subcommand(
+                    App::new("info")
+                        .about("Describe a key string without using it")
+                        .arg_from_usage("<KEY> 'Key or keyfile.'"),
+                )
+                .subcommand(
+                    App::new("export")
+                        .about("Print a key in another encoding")
+                        .arg_from_usage("<KEY> 'Key or keyfile.'")
+                        .arg_from_usage("--bch 'Use the typo-resistant BCH checksum, for keys read aloud or copied from paper.'")
+                        .arg_from_usage("--mnemonic 'Print the key as a list of words, for paper backups.'")
+                        .group(ArgGroup::new("encoding").args(&["bch", "mnemonic"])),
+                )
+                .subcommand(
+                    App::new("import")
+                        .about("Read a key from the words printed by export --mnemonic")
+                        .arg_from_usage("--mnemonic 'Read words, as printed by export --mnemonic. The only encoding import reads, so this may be left out.'")
+                        .arg_from_usage("--bch 'Print the key with the typo-resistant BCH checksum instead of crc16.'")
+                        .arg_from_usage("[WORDS]... 'The words. Read from stdin if omitted.'"),
+                ),
+        )
+        .subcommand(App::new("keygen").about("Generate a key, symmetric unless another type is given")
                     .arg_from_usage("--snow 'Generate Snow keypair'")
                     .arg_from_usage("--symmetric 'Generate symmetric key'")
+                    .arg_from_usage("--deterministic 'Generate deterministic encryption key'")
+                    .arg_from_usage("--wide 'Generate wide-block deterministic encryption key'")
+                    .arg_from_usage("--siv 'Generate deterministic AES-SIV key'")
+                    .arg_from_usage("--fpe 'Generate FF1 format-preserving encryption key'")
+                    .arg_from_usage("--blind-index 'Generate blind index key'")
+                    .arg_from_usage("--psk 'Generate Snow preshared key'")
+                    .arg_from_usage("--opaque=[FILE] 'Wrap the contents of FILE as an opaque key'")
+                    .group(ArgGroup::new("type").args(&["snow", "symmetric", "deterministic", "wide", "siv", "fpe", "blind-index", "psk", "opaque"]))
+                    .arg_from_usage("--out=[FILE] 'Write the key to FILE, readable only by you, instead of stdout. FILE must not exist. A public key is still printed.'")
+                    .arg_from_usage("--json 'Print the type, fingerprint, public key and key as JSON. The key is left out with --out.'")
+                    .arg_from_usage("--bch 'Use the typo-resistant BCH checksum, for keys read aloud or copied from paper.'")
         )
         .get_matches();
 
     if let Some(matches) = matches.subcommand_matches("encrypt") {
-        let key = load_key(matches.value_of("symmetric").expect("validate flags"))?;
-        crate::symmetric_encrypt_sign_file(
-            &key,
-            &mut std::io::stdin().lock(),
-            &mut std::io::stdout().lock(),
-            matches.contains_id("compress"),
-            legacy_format(matches),
-        )?;
+        let input = open_input(matches)?;
+        let mut output = Output::new(matches)?;
+        if let Some(source) = matches.value_of("symmetric") {
+            let key = load_key(matches, source)?;
+            crate::symmetric_encrypt_sign_file(
+                &key,
+                input,
+                &mut output,
+                matches.contains_id("compress"),
+                legacy_format(matches),
+            )?;
+        } else {
+            let passphrase = read_passphrase(matches, /*confirm=*/ true)?;
+            crate::passphrase_encrypt_sign_file(
+                passphrase.as_bytes(),
+                &PassphraseParams::moderate()?,
+                input,
+                &mut output,
+                matches.contains_id("compress"),
+                legacy_format(matches),
+            )?;
+        }
+        output.commit()?;
     } else if let Some(matches) = matches.subcommand_matches("decrypt") {
-        let key = load_key(matches.value_of("symmetric").expect("validate flags"))?;
-        crate::symmetric_decrypt_verify_file(
+        let (format, input) = input_format(matches, open_input(matches)?)?;
+        let mut output = Output::new(matches)?;
+        if let Some(source) = matches.value_of("symmetric") {
+            let key = load_key(matches, source)?;
+            crate::symmetric_decrypt_verify_file(
+                &key,
+                input,
+                &mut output,
+                matches.contains_id("compress"),
+                format,
+            )?;
+        } else {
+            let passphrase = read_passphrase(matches, /*confirm=*/ false)?;
+            crate::passphrase_decrypt_verify_file(
+                passphrase.as_bytes(),
+                input,
+                &mut output,
+                matches.contains_id("compress"),
+                format,
+            )?;
+        }
+        output.commit()?;
+    } else if let Some(matches) = matches.subcommand_matches("det-encrypt") {
+        det_transform(matches, /*encrypt=*/ true)?;
+    } else if let Some(matches) = matches.subcommand_matches("det-decrypt") {
+        det_transform(matches, /*encrypt=*/ false)?;
+    } else if let Some(matches) = matches.subcommand_matches("structured") {
+        if let Some(matches) = matches.subcommand_matches("encrypt") {
+            structured_transform(matches, /*encrypt=*/ true)?;
+        } else if let Some(matches) = matches.subcommand_matches("decrypt") {
+            structured_transform(matches, /*encrypt=*/ false)?;
+        }
+    } else if let Some(matches) = matches.subcommand_matches("archive") {
+        let (name, matches) = matches.subcommand().expect("subcommand required");
+        let key: SymmetricKey = load_key(
+            matches,
+            matches.value_of("symmetric").expect("validate flags"),
+        )?;
+        match name {
+            "create" => {
+                let path = matches.value_of("output").expect("validate flags");
+                let output = AtomicFile::new(path)?;
+                let temp_path = output.temp_path().to_path_buf();
+                let mut archive =
+                    ArchiveWriter::new(output, &key, matches.contains_id("compress"))?;
+                // The output may be inside a directory being archived.
+                archive.skip(&temp_path)?;
+                archive.skip(std::path::Path::new(path))?;
+                for source in matches.values_of("PATH").expect("validate flags") {
+                    archive.add_path(std::path::Path::new(source))?;
+                }
+                archive.finish()?.commit()?;
+            }
+            "list" => {
+                let archive = open_archive(matches, &key)?;
+                if matches.contains_id("json") {
+                    println!("{}", serde_json::to_string_pretty(archive.entries())?);
+                } else {
+                    for entry in archive.entries() {
+                        println!(
+                            "{:04o} {:>12} {:>12} {}",
+                            entry.mode, entry.size, entry.mtime, entry.path
+                        );
+                    }
+                }
+            }
+            _ => {
+                let mut archive = open_archive(matches, &key)?;
+                let dir = std::path::Path::new(matches.value_of("directory").unwrap_or("."));
+                let mode_mask = if matches.is_present("keep-special-bits") {
+                    0o7777
+                } else {
+                    0o777
+                };
+                let wanted: Vec<&str> = matches
+                    .values_of("PATH")
+                    .map(|paths| paths.map(|path| path.trim_end_matches('/')).collect())
+                    .unwrap_or_default();
+                let mut found = vec![false; wanted.len()];
+                for (i, entry) in archive.entries().to_vec().iter().enumerate() {
+                    let mut matched = wanted.is_empty();
+                    for (want, found) in wanted.iter().zip(&mut found) {
+                        if entry.path == *want || entry.path.starts_with(&format!("{}/", want)) {
+                            *found = true;
+                            matched = true;
+                        }
+                    }
+                    if matched {
+                        extract_entry(&mut archive, i, entry, dir, mode_mask)?;
+                    }
+                }
+                if let Some((want, _)) = wanted.iter().zip(&found).find(|(_, found)| !**found) {
+                    anyhow::bail!("{} is not in the archive", want);
+                }
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("edit") {
+        edit_file(matches)?;
+    } else if let Some(matches) = matches.subcommand_matches("exec") {
+        let key: SymmetricKey = load_key(
+            matches,
+            matches.value_of("symmetric").expect("validate flags"),
+        )?;
+        let path = matches.value_of("FILE").expect("validate flags");
+        let input = std::fs::File::open(path).with_context(|| format!("open {}", path))?;
+        let (format, input) = input_format(matches, std::io::BufReader::new(input))?;
+        // The whole file is decrypted and authenticated in memory before
+        // anything runs.
+        let mut cleartext = Vec::default();
+        symmetric_decrypt_verify_file(
             &key,
-            &mut std::io::stdin().lock(),
-            &mut std::io::stdout().lock(),
+            input,
+            &mut cleartext,
             matches.contains_id("compress"),
-            legacy_format(matches),
+            format,
+        )
+        .with_context(|| format!("decrypt {}", path))?;
+        let vars = std::str::from_utf8(&cleartext)
+            .context("not UTF-8")
+            .and_then(parse_dotenv)
+            .with_context(|| format!("parse {}", path));
+        sodiumoxide::utils::memzero(&mut cleartext);
+        let mut args = matches.values_of("COMMAND").expect("validate flags");
+        let program = args.next().expect("validate flags");
+        let mut command = std::process::Command::new(program);
+        command.args(args).envs(vars?);
+        exec(command).with_context(|| format!("run {}", program))?;
+    } else if let Some(matches) = matches.subcommand_matches("rekey") {
+        let from_key: SymmetricKey =
+            load_key(matches, matches.value_of("from").expect("validate flags"))?;
+        let to_key: SymmetricKey =
+            load_key(matches, matches.value_of("to").expect("validate flags"))?;
+        let from_compress = matches.contains_id("compress");
+        let to_compress = match matches.value_of("to-compress") {
+            Some(value) => value == "yes",
+            None => from_compress,
+        };
+        for path in matches.values_of("FILE").expect("validate flags") {
+            let input = std::fs::File::open(path).with_context(|| format!("open {}", path))?;
+            let (from_format, input) = input_format(matches, input)?;
+            let to_format = match matches.value_of("to-legacy") {
+                Some("yes") => Format::Record32,
+                Some(_) => Format::Record,
+                None => from_format,
+            };
+            let mut output = AtomicFile::new_keep_mode(path)?;
+            crate::symmetric_rekey_file(
+                &from_key,
+                &to_key,
+                std::io::BufReader::new(input),
+                &mut output,
+                from_compress,
+                to_compress,
+                from_format,
+                to_format,
+            )
+            .with_context(|| format!("rekey {}", path))?;
+            output.commit()?;
+        }
+    } else if let Some(matches) = matches.subcommand_matches("verify") {
+        let key: SymmetricKey = load_key(
+            matches,
+            matches.value_of("symmetric").expect("validate flags"),
         )?;
+        let compress = matches.contains_id("compress");
+        let paths: Vec<&str> = matches.values_of("FILE").expect("validate flags").collect();
+        let mut failed = 0;
+        for path in &paths {
+            let result = std::fs::File::open(path)
+                .with_context(|| format!("open {}", path))
+                .and_then(|input| input_format(matches, std::io::BufReader::new(input)))
+                .and_then(|(format, input)| {
+                    crate::symmetric_verify_file(&key, input, compress, format)
+                });
+            match result {
+                Ok(()) => println!("{}: OK", path),
+                Err(e) => {
+                    failed += 1;
+                    println!("{}: FAIL ({}): {:#}", path, failure_class(&e), e);
+                }
+            }
+        }
+        if failed > 0 {
+            anyhow::bail!("{} of {} files failed verification", failed, paths.len());
+        }
+    } else if let Some(matches) = matches.subcommand_matches("inspect") {
+        let info = match matches.value_of("FILE") {
+            Some(path) => {
+                let input = std::fs::File::open(path).with_context(|| format!("open {}", path))?;
+                crate::inspect_file(std::io::BufReader::new(input))
+                    .with_context(|| format!("inspect {}", path))?
+            }
+            None => crate::inspect_file(std::io::stdin().lock())?,
+        };
+        if matches.contains_id("json") {
+            println!("{}", serde_json::to_string_pretty(&info)?);
+        } else {
+            print_file_info(&info);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("migrate") {
+        for path in matches.values_of("FILE").expect("validate flags") {
+            let input = std::fs::File::open(path).with_context(|| format!("open {}", path))?;
+            match crate::detect_format(std::io::BufReader::new(input))? {
+                (Some(Format::Record32), input) => {
+                    let mut output = AtomicFile::new_keep_mode(path)?;
+                    crate::reframe_file(input, &mut output, Format::Record32, Format::Record)
+                        .with_context(|| format!("migrate {}", path))?;
+                    output.commit()?;
+                    eprintln!("{}: migrated", path);
+                }
+                (Some(_), _) => eprintln!("{}: already in the current format", path),
+                (None, _) => anyhow::bail!("{}: not an eseb encrypted file", path),
+            }
+        }
+    } else if let Some(matches) = matches.subcommand_matches("key") {
+        if let Some(matches) = matches.subcommand_matches("wrap") {
+            let data = read_key_source(matches.value_of("KEY").expect("validate flags"))?;
+            let passphrase = read_passphrase(matches, /*confirm=*/ true)?;
+            let key: AnyKey = data.parse()?;
+            let wrapped = key.wrap(passphrase.as_bytes(), &PassphraseParams::moderate()?)?;
+            println!("{}", &wrapped.serialize_to_string());
+        } else if let Some(matches) = matches.subcommand_matches("unwrap") {
+            let data = read_key_source(matches.value_of("KEY").expect("validate flags"))?;
+            let wrapped: WrappedKey = data.parse()?;
+            let passphrase = read_passphrase(matches, /*confirm=*/ false)?;
+            println!("{}", &wrapped.unwrap_to_string(passphrase.as_bytes())?);
+        } else if let Some(matches) = matches.subcommand_matches("split") {
+            let data = read_key_source(matches.value_of("KEY").expect("validate flags"))?;
+            let key: AnyKey = data.parse()?;
+            let threshold = matches
+                .value_of("threshold")
+                .expect("validate flags")
+                .parse()
+                .context("parse --threshold")?;
+            let shares = matches
+                .value_of("shares")
+                .expect("validate flags")
+                .parse()
+                .context("parse --shares")?;
+            for share in key.split(threshold, shares)? {
+                println!("{}", &share.serialize_to_string());
+            }
+        } else if let Some(matches) = matches.subcommand_matches("combine") {
+            let mut shares = Vec::default();
+            match matches.values_of("SHARES") {
+                Some(sources) => {
+                    for source in sources {
+                        shares.push(read_key_source(source)?.parse::<KeyShare>()?);
+                    }
+                }
+                None => {
+                    for line in std::io::stdin().lines() {
+                        let line = line.context("read shares")?;
+                        if !line.trim().is_empty() {
+                            shares.push(line.parse::<KeyShare>()?);
+                        }
+                    }
+                }
+            }
+            println!("{}", &KeyShare::combine(&shares)?.serialize_to_string());
+        } else if let Some(matches) = matches.subcommand_matches("derive") {
+            let master: SymmetricKey =
+                load_key(matches, matches.value_of("KEY").expect("validate flags"))?;
+            let path = matches.value_of("PATH").expect("validate flags");
+            let key: AnyKey = match matches.value_of("type").expect("has default") {
+                "deterministic" => master
+                    .derive_path::<DeterministicEncryptionSymmetricKey256>(path)?
+                    .into(),
+                "wide" => master
+                    .derive_path::<WideBlockEncryptionKey256>(path)?
+                    .into(),
+                "siv" => master.derive_path::<DeterministicAeadKey>(path)?.into(),
+                "fpe" => master.derive_path::<FormatPreservingKey>(path)?.into(),
+                "blind-index" => master.derive_path::<BlindIndexKey>(path)?.into(),
+                "psk" => master.derive_path::<SnowPsk>(path)?.into(),
+                _ => master.derive_path::<SymmetricKey>(path)?.into(),
+            };
+            println!("{}", &key.serialize_with(key_encoding(matches)));
+        } else if let Some(matches) = matches.subcommand_matches("info") {
+            let data = read_key_source(matches.value_of("KEY").expect("validate flags"))?;
+            print_key_info(&data)?;
+        } else if let Some(matches) = matches.subcommand_matches("export") {
+            let data = read_key_source(matches.value_of("KEY").expect("validate flags"))?;
+            let key: AnyKey = data.parse()?;
+            if matches.is_present("mnemonic") {
+                println!("{}", &key.to_mnemonic()?);
+            } else {
+                println!("{}", &key.serialize_with(key_encoding(matches)));
+            }
+        } else if let Some(matches) = matches.subcommand_matches("import") {
+            let mnemonic = match matches.values_of("WORDS") {
+                Some(words) => words.collect::<Vec<_>>().join(" "),
+                None => std::io::read_to_string(std::io::stdin()).context("read words")?,
+            };
+            let key = AnyKey::from_mnemonic(&mnemonic)?;
+            println!("{}", &key.serialize_with(key_encoding(matches)));
+        }
     } else if let Some(matches) = matches.subcommand_matches("keygen") {
-        if matches.is_present("snow") {
-            let key = SnowKeyPair::gen_key()?;
-            println!("{}", &key.serialize_to_string());
-            println!("{}", &key.to_public().serialize_to_string());
+        let encoding = key_encoding(matches);
+        let key: AnyKey = if matches.is_present("snow") {
+            SnowKeyPair::gen_key()?.into()
+        } else if matches.is_present("deterministic") {
+            DeterministicEncryptionSymmetricKey256::gen_key()?.into()
+        } else if matches.is_present("wide") {
+            WideBlockEncryptionKey256::gen_key()?.into()
+        } else if matches.is_present("siv") {
+            DeterministicAeadKey::gen_key()?.into()
+        } else if matches.is_present("fpe") {
+            FormatPreservingKey::gen_key()?.into()
+        } else if matches.is_present("blind-index") {
+            BlindIndexKey::gen_key()?.into()
+        } else if matches.is_present("psk") {
+            SnowPsk::gen_key()?.into()
+        } else if let Some(path) = matches.value_of("opaque") {
+            OpaqueKey::new(std::fs::read(path).with_context(|| format!("read {}", path))?).into()
         } else {
-            let key = SymmetricKey::gen_key()?;
-            println!("{}", &key.serialize_to_string());
+            SymmetricKey::gen_key()?.into()
+        };
+        let serialized = key.serialize_with(encoding);
+        let public = key.public_key().map(|key| key.serialize_with(encoding));
+
+        let out = matches.value_of("out");
+        if let Some(path) = out {
+            // Replacing a key would lose whatever was encrypted under it.
+            let mut file = AtomicFile::new(path)?;
+            writeln!(file, "{}", serialized).with_context(|| format!("write {}", path))?;
+            file.commit_new()?;
+        }
+
+        if matches.contains_id("json") {
+            let json = serde_json::json!({
+                "type": key.type_name(),
+                "fingerprint": key.fingerprint(),
+                "public": public,
+                "key": if out.is_some() { None } else { Some(serialized) },
+            });
+            println!("{}", serde_json::to_string_pretty(&json)?);
+        } else {
+            if out.is_none() {
+                println!("{}", serialized);
+            }
+            if let Some(public) = public {
+                println!("{}", public);
+            }
         }
     }
 
     Ok(())
 }
 
+fn open_input(matches: &ArgMatches) -> Result<Box<dyn std::io::BufRead>> {
+    match matches.value_of("input") {
+        Some(path) => {
+            let file = std::fs::File::open(path).with_context(|| format!("open {}", path))?;
+            Ok(Box::new(std::io::BufReader::new(file)))
+        }
+        None => Ok(Box::new(std::io::stdin().lock())),
+    }
+}
+
+// Where encrypt and decrypt write to. Files are written atomically, so a
+// failure part way through never leaves partial output behind.
+enum Output {
+    Stdout(std::io::Stdout),
+    File(AtomicFile),
+}
+
+impl Output {
+    fn new(matches: &ArgMatches) -> Result<Output> {
+        let path = if matches.is_present("in-place") {
+            matches.value_of("input")
+        } else {
+            matches.value_of("output")
+        };
+        match path {
+            Some(path) => Ok(Output::File(AtomicFile::new(path)?)),
+            None => Ok(Output::Stdout(std::io::stdout())),
+        }
+    }
+
+    fn commit(self) -> Result<()> {
+        match self {
+            Output::Stdout(mut stdout) => stdout.flush().context("flush stdout"),
+            Output::File(file) => file.commit(),
+        }
+    }
+}
+
+impl std::io::Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Output::Stdout(stdout) => stdout.write(buf),
+            Output::File(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Output::Stdout(stdout) => stdout.flush(),
+            Output::File(file) => file.flush(),
+        }
+    }
+}
+
+fn det_app<'a>(name: &'a str, about: &'a str) -> App<'a> {
+    App::new(name)
+        .about(about)
+        .arg_from_usage("-e, --deterministic=<KEY> 'Deterministic or wide block encryption key/keyfile.'")
+        .arg(clap::Arg::from_usage("--encoding=[ENCODING] 'How fields are written. Raw fields are 32 bytes each, with no separator.'")
+             .possible_values(["hex", "base64", "raw"])
+             .default_value("hex"))
+        .arg_from_usage("-i, --input=[FILE] 'Read from FILE instead of stdin.'")
+        .arg_from_usage("-o, --output=[FILE] 'Write to FILE instead of stdout. It only appears once everything succeeded.'")
+        .arg(clap::Arg::from_usage("--in-place 'Replace the input file with the output.'")
+             .requires("input")
+             .conflicts_with("output"))
+}
+
+// Encrypts or decrypts each 32-byte field of the input, keeping its encoding.
+// Empty lines pass through, so that missing values stay missing.
+fn det_transform(matches: &ArgMatches, encrypt: bool) -> Result<()> {
+    let key: AnyKey = load_key(
+        matches,
+        matches.value_of("deterministic").expect("validate flags"),
+    )?;
+    type Field = generic_array::GenericArray<u8, generic_array::typenum::U32>;
+    let transform: Box<dyn Fn(Field) -> Field> = match (key, encrypt) {
+        (AnyKey::Deterministic(key), true) => Box::new(move |field| key.encrypt(field)),
+        (AnyKey::Deterministic(key), false) => Box::new(move |field| key.decrypt(field)),
+        (AnyKey::WideBlock(key), true) => Box::new(move |field| key.encrypt(field)),
+        (AnyKey::WideBlock(key), false) => Box::new(move |field| key.decrypt(field)),
+        (key, _) => anyhow::bail!(
+            "expected a deterministic or wide block key, got {}",
+            key.type_name()
+        ),
+    };
+    let apply = |field: &[u8]| transform(*Field::from_slice(field));
+
+    let mut input = open_input(matches)?;
+    let mut output = std::io::BufWriter::new(Output::new(matches)?);
+    match matches.value_of("encoding").expect("default value") {
+        "raw" => {
+            let mut field = [0; 32];
+            while read_field(&mut input, &mut field)? {
+                output.write_all(&apply(&field))?;
+            }
+        }
+        encoding => {
+            // Line endings are copied as they are, a missing one on the last
+            // line included, so that decrypting gives back the input exactly.
+            let mut line = String::default();
+            for i in 1.. {
+                line.clear();
+                if input.read_line(&mut line).context("read input")? == 0 {
+                    break;
+                }
+                let text = line.trim_end_matches(['\r', '\n']);
+                if !text.is_empty() {
+                    let field = match encoding {
+                        "hex" => hex::decode(text).ok(),
+                        _ => base64::decode(text).ok(),
+                    }
+                    .filter(|field| field.len() == 32)
+                    .with_context(|| format!("line {}: not a 32-byte {} field", i, encoding))?;
+                    let field = apply(&field);
+                    match encoding {
+                        "hex" => output.write_all(hex::encode(field).as_bytes())?,
+                        _ => output.write_all(base64::encode(field).as_bytes())?,
+                    }
+                }
+                output.write_all(&line.as_bytes()[text.len()..])?;
+            }
+        }
+    }
+    output
+        .into_inner()
+        .map_err(|e| e.into_error())
+        .context("write output")?
+        .commit()
+}
+
+fn structured_app<'a>(name: &'a str, about: &'a str) -> App<'a> {
+    App::new(name)
+        .about(about)
+        .arg_from_usage("-e, --symmetric=<KEY> 'Symmetric key/keyfile.'")
+        .arg(clap::Arg::from_usage("-f, --format=[FORMAT] 'Document format. Guessed from the file extension otherwise.'")
+             .possible_values(["json", "yaml", "toml"]))
+        .arg_from_usage("-i, --input=[FILE] 'Read from FILE instead of stdin.'")
+        .arg_from_usage("-o, --output=[FILE] 'Write to FILE instead of stdout. It only appears once everything succeeded.'")
+        .arg(clap::Arg::from_usage("--in-place 'Replace the input file with the output.'")
+             .requires("input")
+             .conflicts_with("output"))
+}
+
+fn structured_transform(matches: &ArgMatches, encrypt: bool) -> Result<()> {
+    let key: SymmetricKey = load_key(
+        matches,
+        matches.value_of("symmetric").expect("validate flags"),
+    )?;
+    let format = match matches.value_of("format") {
+        Some(format) => format.parse()?,
+        None => matches
+            .value_of("input")
+            .or_else(|| matches.value_of("output"))
+            .and_then(StructuredFormat::from_path)
+            .context("cannot tell the document format; use --format")?,
+    };
+    let mut data = String::default();
+    open_input(matches)?
+        .read_to_string(&mut data)
+        .context("read input")?;
+    let data = if encrypt {
+        structured_encrypt(&key, &data, format)?
+    } else {
+        structured_decrypt(&key, &data, format)?
+    };
+    let mut output = Output::new(matches)?;
+    output.write_all(data.as_bytes())?;
+    output.commit()
+}
+
+// Fills `field`, or returns false at a clean end of input.
+fn read_field<R: std::io::Read>(input: &mut R, field: &mut [u8]) -> Result<bool> {
+    let mut filled = 0;
+    while filled < field.len() {
+        match input.read(&mut field[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => anyhow::bail!("input ends part way through a 32-byte field"),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(Error::new(e).context("read input")),
+        }
+    }
+    Ok(true)
+}
+
 fn legacy_format(matches: &ArgMatches) -> Format {
     if matches.contains_id("legacy") {
         Format::Record32
@@ -76,15 +782,453 @@ fn legacy_format(matches: &ArgMatches) -> Format {
     }
 }
 
-fn load_key(source: &str) -> Result<SymmetricKey> {
-    source
-        .parse::<SymmetricKey>()
-        .or_else(|_| std::fs::read_to_string(source)?.parse::<SymmetricKey>())
+// --legacy forces the legacy format, otherwise it is detected from the data.
+fn input_format<R: std::io::Read>(
+    matches: &ArgMatches,
+    reader: R,
+) -> Result<(Format, impl std::io::Read)> {
+    let (detected, reader) = crate::detect_format(reader)?;
+    let format = if matches.contains_id("legacy") {
+        Format::Record32
+    } else {
+        detected.unwrap_or(Format::Record)
+    };
+    Ok((format, reader))
+}
+
+// What went wrong with a file, for `verify` reports.
+fn failure_class(e: &Error) -> &'static str {
+    if let Some(e) = e.downcast_ref::<StreamError>() {
+        return e.class();
+    }
+    match e.root_cause().downcast_ref::<std::io::Error>() {
+        Some(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => "truncated",
+        _ => "io",
+    }
+}
+
+fn load_key<K>(matches: &ArgMatches, source: &str) -> Result<K>
+where
+    K: TryFrom<AnyKey>,
+    K::Error: Into<Error>,
+{
+    let key = match read_key_source(source)?.parse()? {
+        AnyKey::Wrapped(wrapped) => {
+            let passphrase = match matches.value_of("key-passphrase-fd") {
+                Some(fd) => read_passphrase_fd(fd.parse().context("parse --key-passphrase-fd")?)?,
+                None => prompt_passphrase("Passphrase for wrapped key: ")?,
+            };
+            wrapped.unwrap_key::<AnyKey>(passphrase.as_bytes())?
+        }
+        key => key,
+    };
+    key.try_into().map_err(Into::into)
+}
+
+// Keys may be given inline or as the path of a file containing one. Anything
+// that does not start with a known key header is taken as a path.
+fn read_key_source(source: &str) -> Result<String> {
+    if AnyKey::detect_header(source).is_some() || has_header(source.trim(), KeyShare::HEADER) {
+        Ok(source.to_string())
+    } else {
+        std::fs::read_to_string(source).with_context(|| format!("read key file {}", source))
+    }
+}
+
+fn key_encoding(matches: &ArgMatches) -> KeyEncoding {
+    if matches.is_present("bch") {
+        KeyEncoding::Bch
+    } else {
+        KeyEncoding::Crc16
+    }
+}
+
+fn print_key_info(data: &str) -> Result<()> {
+    let data = data.trim();
+    let header = AnyKey::detect_header(data).context("not a recognized key string")?;
+    let version = header_version(data);
+    println!("type: {}", header_type(header));
+    println!("version: {}", version);
+    if version == BCH_VERSION {
+        println!("encoding: bch");
+    } else {
+        println!("encoding: crc16");
+    }
+    match data.parse::<AnyKey>() {
+        Ok(key) => {
+            println!("fingerprint: {}", key.fingerprint());
+            println!("checksum: ok");
+            Ok(())
+        }
+        Err(e) => {
+            // The checksum is checked before anything else in the payload.
+            match e.downcast_ref::<KeyParseError>() {
+                Some(KeyParseError::MissingCrc) => println!("checksum: missing"),
+                Some(KeyParseError::BadCrc { expected, actual }) => {
+                    println!(
+                        "checksum: bad (expected {}, calculated {})",
+                        expected, actual
+                    )
+                }
+                Some(e @ KeyParseError::BadChecksum { .. })
+                | Some(e @ KeyParseError::InvalidCharacter { .. }) => {
+                    println!("checksum: {}", e)
+                }
+                Some(KeyParseError::UnsupportedVersion { .. }) => println!("checksum: unknown"),
+                // These are only found once the checksum matched.
+                Some(KeyParseError::BadBase64 { .. })
+                | Some(KeyParseError::WrongLength { .. })
+                | Some(KeyParseError::InvalidPayload(_)) => println!("checksum: ok"),
+                // Anything else failed before the checksum was looked at.
+                _ => {}
+            }
+            Err(e.context("invalid key"))
+        }
+    }
+}
+
+// A passphrase, wiped from memory when dropped.
+struct Passphrase(Vec<u8>);
+
+impl Passphrase {
+    fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Drop for Passphrase {
+    fn drop(&mut self) {
+        sodiumoxide::utils::memzero(&mut self.0);
+    }
+}
+
+// The passphrase never comes from argv, where other users can see it. stdin
+// carries the data, so the prompt goes to the controlling TTY.
+fn read_passphrase(matches: &ArgMatches, confirm: bool) -> Result<Passphrase> {
+    let passphrase = match matches.value_of("passphrase-fd") {
+        Some(fd) => read_passphrase_fd(fd.parse().context("parse --passphrase-fd")?)?,
+        None => {
+            let passphrase = prompt_passphrase("Passphrase: ")?;
+            if confirm && passphrase.0 != prompt_passphrase("Confirm passphrase: ")?.0 {
+                anyhow::bail!("passphrases do not match");
+            }
+            passphrase
+        }
+    };
+
+    if passphrase.0.is_empty() {
+        anyhow::bail!("empty passphrase");
+    }
+
+    Ok(passphrase)
+}
+
+fn prompt_passphrase(prompt: &str) -> Result<Passphrase> {
+    let passphrase = rpassword::prompt_password(prompt).context("read passphrase")?;
+    Ok(Passphrase(passphrase.into_bytes()))
+}
+
+// Reads one line from `fd`, which stays open, and byte by byte, so that the
+// next call, e.g. for a second wrapped key, reads the next line.
+#[cfg(unix)]
+fn read_passphrase_fd(fd: std::os::unix::io::RawFd) -> Result<Passphrase> {
+    use std::os::unix::io::BorrowedFd;
+
+    if fd <= 2 {
+        anyhow::bail!("--passphrase-fd must not be stdin, stdout or stderr");
+    }
+
+    // SAFETY: the caller handed us this descriptor to read from. It is only
+    // borrowed long enough to duplicate it, which fails if it is not open.
+    let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+    let mut file = std::fs::File::from(fd.try_clone_to_owned().context("duplicate passphrase fd")?);
+    let mut line = Passphrase(Vec::default());
+    let mut byte = [0];
+    let mut any = false;
+    loop {
+        match file.read(&mut byte) {
+            Ok(0) if !any => anyhow::bail!("no passphrase left to read from fd"),
+            Ok(0) => break,
+            Ok(_) if byte[0] == b'\n' => break,
+            Ok(_) => {
+                any = true;
+                line.0.push(byte[0]);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e).context("read passphrase from fd"),
+        }
+    }
+    if line.0.ends_with(b"\r") {
+        line.0.pop();
+    }
+    Ok(line)
+}
+
+#[cfg(not(unix))]
+fn read_passphrase_fd(_fd: i32) -> Result<Passphrase> {
+    anyhow::bail!("--passphrase-fd is only supported on Unix")
+}
+
+fn print_file_info(info: &FileInfo) {
+    println!("framing: {}", info.framing);
+    match &info.passphrase {
+        Some(passphrase) => {
+            println!("key: passphrase (argon2id)");
+            println!("opslimit: {}", passphrase.opslimit);
+            println!("memlimit: {}", passphrase.memlimit);
+            println!("salt: {}", passphrase.salt);
+        }
+        None => println!("key: symmetric"),
+    }
+    println!("stream header: {}", info.stream_header);
+    println!("records: {}", info.records);
+    let sizes = &info.record_sizes;
+    println!(
+        "record size: min {}, mean {}, max {}",
+        sizes.min, sizes.mean, sizes.max
+    );
+    for bucket in &sizes.histogram {
+        println!("  <= {:>10}: {}", bucket.up_to, bucket.count);
+    }
+    println!("file bytes: {}", info.file_bytes);
+    println!("payload bytes: {}", info.payload_bytes);
+    println!("overhead bytes: {}", info.overhead_bytes);
+}
+
+fn open_archive(matches: &ArgMatches, key: &SymmetricKey) -> Result<ArchiveReader<std::fs::File>> {
+    let path = matches.value_of("ARCHIVE").expect("validate flags");
+    let file = std::fs::File::open(path).with_context(|| format!("open {}", path))?;
+    ArchiveReader::new(file, key).with_context(|| format!("read {}", path))
+}
+
+// Writes a member under `dir` atomically, then sets its mtime and the bits of
+// its mode in `mode_mask`.
+fn extract_entry(
+    archive: &mut ArchiveReader<std::fs::File>,
+    index: usize,
+    entry: &ArchiveEntry,
+    dir: &std::path::Path,
+    mode_mask: u32,
+) -> Result<()> {
+    // Paths are checked to be relative without `..`, but a directory on the
+    // way could still be a symlink out of `dir`.
+    let mut path = dir.to_path_buf();
+    let mut names = entry.path.split('/').peekable();
+    while let Some(name) = names.next() {
+        path.push(name);
+        if names.peek().is_none() {
+            break;
+        }
+        match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                anyhow::bail!("{} is a symlink, not extracting through it", path.display())
+            }
+            Ok(metadata) if !metadata.is_dir() => {
+                anyhow::bail!("{} is not a directory", path.display())
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                std::fs::create_dir(&path).with_context(|| format!("create {}", path.display()))?
+            }
+            Err(e) => return Err(e).with_context(|| format!("stat {}", path.display())),
+        }
+    }
+    let mut output = AtomicFile::new(&path)?;
+    archive.extract(index, &mut output)?;
+    output.commit()?;
+    let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(entry.mtime.max(0) as u64);
+    std::fs::File::options()
+        .write(true)
+        .open(&path)
+        .and_then(|file| file.set_modified(mtime))
+        .with_context(|| format!("set mtime of {}", path.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(
+            &path,
+            std::fs::Permissions::from_mode(entry.mode & mode_mask),
+        )
+        .with_context(|| format!("set mode of {}", path.display()))?;
+    }
+    eprintln!("{}", entry.path);
+    Ok(())
+}
+
+// Decrypts into a scratch file, runs the editor on it and re-encrypts it over
+// the original, in the same format, if it changed.
+fn edit_file(matches: &ArgMatches) -> Result<()> {
+    let key: SymmetricKey = load_key(
+        matches,
+        matches.value_of("symmetric").expect("validate flags"),
+    )?;
+    let path = matches.value_of("FILE").expect("validate flags");
+    let compress = matches.contains_id("compress");
+    let input = std::fs::File::open(path).with_context(|| format!("open {}", path))?;
+    let (format, input) = input_format(matches, std::io::BufReader::new(input))?;
+    let mut cleartext = Vec::default();
+    let decrypted = symmetric_decrypt_verify_file(&key, input, &mut cleartext, compress, format)
+        .with_context(|| format!("decrypt {}", path));
+    let edited = decrypted.and_then(|()| {
+        // From here on a signal must not kill us before the scratch file is
+        // wiped; it stops the editor instead. Ctrl-C is sent to the editor
+        // too, which handles it itself, so like git we ignore it. A handler
+        // rather than SIG_IGN, so that the editor does not inherit that.
+        let terminated = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        for signal in TERM_SIGNALS {
+            signal_hook::flag::register(*signal, terminated.clone())
+                .context("install signal handler")?;
+        }
+        signal_hook::flag::register(
+            signal_hook::consts::SIGINT,
+            std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false)),
+        )
+        .context("install signal handler")?;
+        let scratch = ScratchFile::new(path, &cleartext)?;
+        run_editor(scratch.path(), &terminated)?;
+        scratch.read()
+    });
+    let result = edited.and_then(|mut edited| {
+        let changed =
+            !(edited.len() == cleartext.len() && sodiumoxide::utils::memcmp(&edited, &cleartext));
+        let result = if changed {
+            let mut output = AtomicFile::new_keep_mode(path)?;
+            symmetric_encrypt_sign_file(&key, &edited[..], &mut output, compress, format)
+                .and_then(|()| output.commit())
+                .with_context(|| format!("encrypt {}", path))
+        } else {
+            eprintln!("{}: unchanged", path);
+            Ok(())
+        };
+        sodiumoxide::utils::memzero(&mut edited);
+        result
+    });
+    sodiumoxide::utils::memzero(&mut cleartext);
+    result
+}
+
+#[cfg(unix)]
+const TERM_SIGNALS: &[i32] = &[signal_hook::consts::SIGTERM, signal_hook::consts::SIGHUP];
+
+#[cfg(not(unix))]
+const TERM_SIGNALS: &[i32] = &[signal_hook::consts::SIGTERM];
+
+// Runs $VISUAL or $EDITOR, which may include arguments, on `path`. Kills it
+// if `terminated` is set.
+fn run_editor(path: &std::path::Path, terminated: &std::sync::atomic::AtomicBool) -> Result<()> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    let mut words = editor.split_whitespace();
+    let program = words.next().context("$EDITOR is empty")?;
+    let mut child = std::process::Command::new(program)
+        .args(words)
+        .arg(path)
+        .spawn()
+        .with_context(|| format!("run {}", editor))?;
+    loop {
+        if terminated.load(std::sync::atomic::Ordering::Relaxed) {
+            // It may well have exited on the same signal already.
+            let _ = child.kill();
+            let _ = child.wait();
+            anyhow::bail!("terminated; the file is unchanged");
+        }
+        match child.try_wait().context("wait for editor")? {
+            Some(status) if status.success() => return Ok(()),
+            Some(status) => anyhow::bail!("{} failed ({}); the file is unchanged", editor, status),
+            None => std::thread::sleep(std::time::Duration::from_millis(50)),
+        }
+    }
+}
+
+// The cleartext being edited: a file readable only by the owner, in a private
+// directory on tmpfs when there is one, so that it never reaches a disk. It
+// is overwritten with zeros before it is deleted.
+struct ScratchFile {
+    path: std::path::PathBuf,
+    _dir: tempfile::TempDir,
+}
+
+impl ScratchFile {
+    fn new(name: &str, data: &[u8]) -> Result<ScratchFile> {
+        let mut builder = tempfile::Builder::new();
+        builder.prefix("eseb-edit-");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            builder.permissions(std::fs::Permissions::from_mode(0o700));
+        }
+        let shm = std::path::Path::new("/dev/shm");
+        let dir = if shm.is_dir() {
+            builder.tempdir_in(shm)
+        } else {
+            builder.tempdir()
+        }
+        .context("create temporary directory")?;
+
+        // Keep the file name, less a .eseb extension, so that the editor can
+        // tell the file type.
+        let name = std::path::Path::new(name);
+        let name = match name.extension() {
+            Some(ext) if ext == "eseb" => name.file_stem(),
+            _ => name.file_name(),
+        }
+        .unwrap_or_else(|| "file".as_ref());
+        let path = dir.path().join(name);
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let scratch = ScratchFile { path, _dir: dir };
+        options
+            .open(&scratch.path)
+            .and_then(|mut file| file.write_all(data))
+            .with_context(|| format!("write {}", scratch.path.display()))?;
+        Ok(scratch)
+    }
+
+    fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+
+    fn read(&self) -> Result<Vec<u8>> {
+        std::fs::read(&self.path).with_context(|| format!("read {}", self.path.display()))
+    }
+}
+
+impl Drop for ScratchFile {
+    fn drop(&mut self) {
+        // Editors often replace the file rather than write to it, so this
+        // only wipes the latest version. The directory goes with `_dir`.
+        if let Ok(mut file) = std::fs::OpenOptions::new().write(true).open(&self.path) {
+            let len = file.metadata().map_or(0, |m| m.len());
+            let _ = std::io::copy(&mut std::io::repeat(0).take(len), &mut file)
+                .and_then(|_| file.sync_all());
+        }
+    }
+}
+
+// Replaces this process with `command`, so that its exit status is ours.
+// Only returns on failure.
+#[cfg(unix)]
+fn exec(mut command: std::process::Command) -> Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    Err(command.exec().into())
+}
+
+#[cfg(not(unix))]
+fn exec(mut command: std::process::Command) -> Result<()> {
+    let status = command.status()?;
+    std::process::exit(status.code().unwrap_or(1))
 }
 
 fn main() {
     if let Err(e) = fmain() {
-        eprintln!("error: {}", &e);
+        eprintln!("error: {:#}", &e);
         std::process::exit(-1);
     }
 }
@@ -0,0 +1,292 @@
+//! Key strings as a list of words from the BIP39 English wordlist, for keys
+//! escrowed on paper. Each word carries 11 bits of
+//! `[type tag][length][key bytes][checksum]`, so the words alone are enough
+//! to rebuild the key string, type included.
+
+use anyhow::{Error, Result};
+use sodiumoxide::crypto::generichash;
+
+use crate::key_util::{append_serialized, KeyParseError};
+use crate::{
+    BlindIndexKey, DeterministicAeadKey, DeterministicEncryptionSymmetricKey256,
+    FormatPreservingKey, KeyMaterial, KeyShare, OpaqueKey, SnowKeyPair, SnowPrivateKey, SnowPsk,
+    SnowPublicKey, SymmetricKey, WideBlockEncryptionKey256, WrappedKey,
+};
+
+// The tag is all that records the key type in a mnemonic, so never renumber
+// these or reuse a retired tag.
+const KEY_TYPES: &[(u8, &str)] = &[
+    (0, SymmetricKey::HEADER),
+    (1, DeterministicEncryptionSymmetricKey256::HEADER),
+    (2, OpaqueKey::HEADER),
+    (3, SnowKeyPair::HEADER),
+    (4, SnowPublicKey::HEADER),
+    (5, SnowPrivateKey::HEADER),
+    (6, SnowPsk::HEADER),
+    (7, WrappedKey::HEADER),
+    (8, KeyShare::HEADER),
+    (9, DeterministicAeadKey::HEADER),
+    (10, WideBlockEncryptionKey256::HEADER),
+    (11, FormatPreservingKey::HEADER),
+    (12, BlindIndexKey::HEADER),
+];
+
+const CHECKSUM_LEN: usize = 4;
+const BITS_PER_WORD: usize = 11;
+
+// Every word in the list is unique in its first 4 letters, which is all that
+// people engraving backups tend to keep.
+const PREFIX_LEN: usize = 4;
+
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let mut state =
+        generichash::State::new(Some(generichash::DIGEST_MIN), Some(b"eseb mnemonic v1"))
+            .expect("valid blake2b parameters");
+    state.update(data).expect("hash mnemonic");
+    let digest = state.finalize().expect("finalize blake2b");
+    digest.as_ref()[..CHECKSUM_LEN]
+        .try_into()
+        .expect("digest is longer than the checksum")
+}
+
+fn word_count(bytes: usize) -> usize {
+    (bytes * 8).div_ceil(BITS_PER_WORD)
+}
+
+fn find_word(word: &str) -> Option<u16> {
+    let word = word.to_ascii_lowercase();
+    let words = bip39::Language::English.word_list();
+    if let Some(index) = words.iter().position(|w| *w == word) {
+        return Some(index as u16);
+    }
+    if word.len() < PREFIX_LEN {
+        return None;
+    }
+    words
+        .iter()
+        .position(|w| w.len() > word.len() && w.starts_with(&word))
+        .map(|index| index as u16)
+}
+
+/// Encodes the key `key` of the type with header `header` as words.
+pub(crate) fn encode(header: &str, key: &[u8]) -> Result<String> {
+    let tag = KEY_TYPES
+        .iter()
+        .find(|(_, h)| *h == header)
+        .map(|(tag, _)| *tag)
+        .ok_or_else(|| Error::msg(format!("no mnemonic encoding for {}", header)))?;
+    let len: u8 = key.len().try_into().map_err(|_| {
+        Error::msg(format!(
+            "key too long for a mnemonic ({} bytes, at most 255)",
+            key.len()
+        ))
+    })?;
+
+    let mut data = vec![tag, len];
+    data.extend_from_slice(key);
+    let checksum = checksum(&data);
+    data.extend_from_slice(&checksum);
+
+    let words = bip39::Language::English.word_list();
+    let mut v = Vec::with_capacity(word_count(data.len()));
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for byte in &data {
+        acc = acc << 8 | *byte as u32;
+        bits += 8;
+        while bits >= BITS_PER_WORD {
+            bits -= BITS_PER_WORD;
+            v.push(words[(acc >> bits) as usize & 0x7ff]);
+        }
+        acc &= (1 << bits) - 1;
+    }
+    if bits > 0 {
+        v.push(words[(acc << (BITS_PER_WORD - bits)) as usize & 0x7ff]);
+    }
+    Ok(v.join(" "))
+}
+
+/// Decodes the output of `encode` back into a key string in the default
+/// encoding. Words may be abbreviated to their first 4 letters.
+pub(crate) fn decode(mnemonic: &str) -> Result<String, KeyParseError> {
+    let mut indices = Vec::default();
+    for (i, word) in mnemonic.split_whitespace().enumerate() {
+        indices.push(find_word(word).ok_or(KeyParseError::UnknownWord { word: i + 1 })?);
+    }
+
+    let mut data = Vec::with_capacity(indices.len() * BITS_PER_WORD / 8);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for index in &indices {
+        acc = acc << BITS_PER_WORD | *index as u32;
+        bits += BITS_PER_WORD;
+        while bits >= 8 {
+            bits -= 8;
+            data.push((acc >> bits) as u8);
+        }
+        acc &= (1 << bits) - 1;
+    }
+
+    if data.len() < 2 + CHECKSUM_LEN {
+        return Err(KeyParseError::InvalidPayload("too few words".to_string()));
+    }
+    let len = 2 + data[1] as usize + CHECKSUM_LEN;
+    if indices.len() != word_count(len) {
+        return Err(KeyParseError::InvalidPayload(format!(
+            "expected {} words, got {}",
+            word_count(len),
+            indices.len()
+        )));
+    }
+
+    // The last word may be padded with up to 10 zero bits.
+    let bad_checksum = KeyParseError::BadChecksum {
+        positions: Vec::default(),
+    };
+    if acc != 0 || data[len..].iter().any(|byte| *byte != 0) {
+        return Err(bad_checksum);
+    }
+    data.truncate(len);
+    let (data, expected) = data.split_at(len - CHECKSUM_LEN);
+    if checksum(data) != expected {
+        return Err(bad_checksum);
+    }
+
+    let header = KEY_TYPES
+        .iter()
+        .find(|(tag, _)| *tag == data[0])
+        .map(|(_, header)| header)
+        .ok_or_else(|| KeyParseError::InvalidPayload(format!("unknown key type {}", data[0])))?;
+    let mut v = String::default();
+    append_serialized(&mut v, header, &data[2..]);
+    Ok(v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::AnyKey;
+
+    fn words(mnemonic: &str) -> Vec<&str> {
+        mnemonic.split(' ').collect()
+    }
+
+    #[test]
+    fn test_every_key_type_has_a_tag() {
+        for header in AnyKey::HEADERS {
+            assert!(
+                KEY_TYPES.iter().any(|(_, h)| h == header),
+                "{} has no mnemonic tag",
+                header
+            );
+        }
+        for (i, (tag, _)) in KEY_TYPES.iter().enumerate() {
+            assert!(KEY_TYPES[..i].iter().all(|(t, _)| t != tag));
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let pair = SnowKeyPair::gen_key().unwrap();
+        let keys: Vec<AnyKey> = vec![
+            SymmetricKey::gen_key().unwrap().into(),
+            DeterministicEncryptionSymmetricKey256::gen_key()
+                .unwrap()
+                .into(),
+            DeterministicAeadKey::gen_key().unwrap().into(),
+            WideBlockEncryptionKey256::gen_key().unwrap().into(),
+            FormatPreservingKey::gen_key().unwrap().into(),
+            BlindIndexKey::gen_key().unwrap().into(),
+            OpaqueKey::new(vec![]).into(),
+            pair.to_public().into(),
+            pair.to_private().into(),
+            pair.to_psk().into(),
+            pair.into(),
+        ];
+        for key in keys {
+            let mnemonic = key.to_mnemonic().unwrap();
+            let decoded = AnyKey::from_mnemonic(&mnemonic).unwrap();
+            assert_eq!(decoded.header(), key.header());
+            assert_eq!(decoded.key_bytes(), key.key_bytes());
+        }
+    }
+
+    #[test]
+    fn test_symmetric_key_length() {
+        // 1 + 1 + 32 + 4 bytes.
+        let key = SymmetricKey::gen_key().unwrap();
+        assert_eq!(words(&key.to_mnemonic().unwrap()).len(), 28);
+    }
+
+    #[test]
+    fn test_abbreviations_and_case() {
+        let key = SymmetricKey::gen_key().unwrap();
+        let mnemonic = key.to_mnemonic().unwrap();
+        let short: Vec<String> = words(&mnemonic)
+            .iter()
+            .map(|w| {
+                w.chars()
+                    .take(PREFIX_LEN)
+                    .collect::<String>()
+                    .to_uppercase()
+            })
+            .collect();
+        let decoded = AnyKey::from_mnemonic(&format!("  {}\n", short.join("\n  "))).unwrap();
+        assert_eq!(decoded.key_bytes(), key.key_bytes());
+    }
+
+    #[test]
+    fn test_unknown_word() {
+        let mnemonic = SymmetricKey::gen_key().unwrap().to_mnemonic().unwrap();
+        let mut bad = words(&mnemonic);
+        bad[4] = "zzzz";
+        assert_eq!(
+            decode(&bad.join(" ")),
+            Err(KeyParseError::UnknownWord { word: 5 })
+        );
+
+        // Too short to be an abbreviation.
+        let mut bad = words(&mnemonic);
+        let prefix = &bad[9][..2];
+        bad[9] = prefix;
+        assert_eq!(
+            decode(&bad.join(" ")),
+            Err(KeyParseError::UnknownWord { word: 10 })
+        );
+    }
+
+    #[test]
+    fn test_wrong_word() {
+        let mnemonic = SymmetricKey::gen_key().unwrap().to_mnemonic().unwrap();
+        let list = bip39::Language::English.word_list();
+        for position in 0..words(&mnemonic).len() {
+            let mut bad = words(&mnemonic);
+            let index = find_word(bad[position]).unwrap() as usize;
+            bad[position] = list[(index + 1 + position * 31) % list.len()];
+            assert!(decode(&bad.join(" ")).is_err(), "word {}", position);
+        }
+    }
+
+    #[test]
+    fn test_swapped_and_missing_words() {
+        let mnemonic = SymmetricKey::gen_key().unwrap().to_mnemonic().unwrap();
+        let mut bad = words(&mnemonic);
+        let position = (1..bad.len()).find(|i| bad[*i] != bad[i - 1]).unwrap();
+        bad.swap(position - 1, position);
+        assert!(decode(&bad.join(" ")).is_err());
+
+        let mut bad = words(&mnemonic);
+        bad.pop();
+        assert!(decode(&bad.join(" ")).is_err());
+        assert!(decode("").is_err());
+    }
+
+    #[test]
+    fn test_too_long() {
+        // Opaque keys are compressed, so use data that does not compress.
+        let data = sodiumoxide::randombytes::randombytes(300);
+        assert!(OpaqueKey::new(data).to_mnemonic().is_err());
+        assert!(OpaqueKey::new(vec![0; 1000]).to_mnemonic().is_ok());
+    }
+}
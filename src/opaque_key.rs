@@ -17,7 +17,9 @@ impl std::str::FromStr for OpaqueKey {
         let enc_data = parse_header(data.trim(), &Self::HEADER)?;
         let mut decompressor = brotli::reader::Decompressor::new(&*enc_data, 8192);
         let mut data = Vec::default();
-        decompressor.read_to_end(&mut data)?;
+        decompressor
+            .read_to_end(&mut data)
+            .map_err(|e| KeyParseError::InvalidPayload(format!("decompress: {}", e)))?;
         Ok(OpaqueKey { data })
     }
 }
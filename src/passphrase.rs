@@ -0,0 +1,174 @@
+use anyhow::{Context, Error, Result};
+use sodiumoxide::crypto::generichash;
+use sodiumoxide::crypto::pwhash::argon2id13;
+
+use crate::SymmetricKey;
+
+const MAGIC: &[u8; 8] = b"esebpwd1";
+const SALT_LEN: usize = argon2id13::SALTBYTES;
+pub(crate) const ENCODED_LEN: usize = MAGIC.len() + 8 + 8 + SALT_LEN;
+
+/// Argon2id cost parameters and salt used to turn a passphrase into a
+/// `SymmetricKey`.
+///
+/// These are written in the clear as the first record of a passphrase
+/// encrypted file. They are authenticated by mixing the encoded header into
+/// the derived key, so tampering with the salt or costs yields the wrong key
+/// and the stream fails to decrypt.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PassphraseParams {
+    opslimit: u64,
+    memlimit: u64,
+    salt: [u8; SALT_LEN],
+}
+
+impl PassphraseParams {
+    /// Fresh random salt with the given Argon2id costs.
+    pub fn new(opslimit: u64, memlimit: u64) -> Result<PassphraseParams> {
+        let salt = argon2id13::gen_salt();
+        let params = PassphraseParams {
+            opslimit,
+            memlimit,
+            salt: salt.0,
+        };
+        params.check_limits()?;
+        Ok(params)
+    }
+
+    /// libsodium's "interactive" costs. Fast, intended for tests and keys
+    /// that are re-derived often.
+    pub fn interactive() -> Result<PassphraseParams> {
+        Self::new(
+            argon2id13::OPSLIMIT_INTERACTIVE.0 as u64,
+            argon2id13::MEMLIMIT_INTERACTIVE.0 as u64,
+        )
+    }
+
+    /// libsodium's "moderate" costs. This is what the CLI uses.
+    pub fn moderate() -> Result<PassphraseParams> {
+        Self::new(
+            argon2id13::OPSLIMIT_MODERATE.0 as u64,
+            argon2id13::MEMLIMIT_MODERATE.0 as u64,
+        )
+    }
+
+    pub fn opslimit(&self) -> u64 {
+        self.opslimit
+    }
+
+    pub fn memlimit(&self) -> u64 {
+        self.memlimit
+    }
+
+    pub fn salt(&self) -> &[u8] {
+        &self.salt
+    }
+
+    pub fn derive_key(&self, passphrase: &[u8]) -> Result<SymmetricKey> {
+        self.check_limits()?;
+        let salt = argon2id13::Salt(self.salt);
+        let mut stretched = [0; 32];
+        argon2id13::derive_key(
+            &mut stretched,
+            passphrase,
+            &salt,
+            argon2id13::OpsLimit(self.opslimit as usize),
+            argon2id13::MemLimit(self.memlimit as usize),
+        )
+        .map_err(|_| Error::msg("argon2id key derivation failed"))?;
+
+        let mut state = generichash::State::new(Some(32), Some(&stretched))
+            .map_err(|_| Error::msg("init blake2b"))?;
+        sodiumoxide::utils::memzero(&mut stretched);
+        state
+            .update(&self.to_bytes())
+            .map_err(|_| Error::msg("hash passphrase header"))?;
+        let digest = state
+            .finalize()
+            .map_err(|_| Error::msg("finalize blake2b"))?;
+        SymmetricKey::from_slice(digest.as_ref())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(ENCODED_LEN);
+        v.extend_from_slice(MAGIC);
+        v.extend_from_slice(&self.opslimit.to_be_bytes());
+        v.extend_from_slice(&self.memlimit.to_be_bytes());
+        v.extend_from_slice(&self.salt);
+        v
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<PassphraseParams> {
+        if !Self::is_header(data) {
+            anyhow::bail!("not a passphrase header");
+        }
+        let (opslimit, rest) = data[MAGIC.len()..].split_at(8);
+        let (memlimit, salt) = rest.split_at(8);
+        let params = PassphraseParams {
+            opslimit: u64::from_be_bytes(opslimit.try_into().expect("split at 8")),
+            memlimit: u64::from_be_bytes(memlimit.try_into().expect("split at 8")),
+            salt: salt.try_into().expect("is_header checked the length"),
+        };
+        params.check_limits().context("passphrase header")?;
+        Ok(params)
+    }
+
+    /// Whether a record looks like an encoded `PassphraseParams`.
+    pub fn is_header(data: &[u8]) -> bool {
+        data.len() == ENCODED_LEN && data.starts_with(MAGIC)
+    }
+
+    // The header is attacker controlled, so refuse costs that would let a
+    // crafted file eat all our memory or CPU before we can reject it.
+    fn check_limits(&self) -> Result<()> {
+        if self.opslimit < argon2id13::OPSLIMIT_INTERACTIVE.0 as u64
+            || self.opslimit > argon2id13::OPSLIMIT_SENSITIVE.0 as u64
+        {
+            anyhow::bail!("argon2id opslimit {} out of range", self.opslimit);
+        }
+        if self.memlimit < argon2id13::MEMLIMIT_INTERACTIVE.0 as u64
+            || self.memlimit > argon2id13::MEMLIMIT_SENSITIVE.0 as u64
+        {
+            anyhow::bail!("argon2id memlimit {} out of range", self.memlimit);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::KeyMaterial;
+
+    #[test]
+    fn test_derive_key() {
+        let params = PassphraseParams::interactive().unwrap();
+        let key1 = params.derive_key(b"correct horse").unwrap();
+        let key2 = params.derive_key(b"correct horse").unwrap();
+        let key3 = params.derive_key(b"battery staple").unwrap();
+        assert_eq!(key1.key_bytes(), key2.key_bytes());
+        assert_ne!(key1.key_bytes(), key3.key_bytes());
+
+        let other = PassphraseParams::interactive().unwrap();
+        assert_ne!(params.salt(), other.salt());
+        assert_ne!(
+            other.derive_key(b"correct horse").unwrap().key_bytes(),
+            key1.key_bytes()
+        );
+    }
+
+    #[test]
+    fn test_header_round_trip() {
+        let params = PassphraseParams::interactive().unwrap();
+        let data = params.to_bytes();
+        assert!(PassphraseParams::is_header(&data));
+        assert_eq!(PassphraseParams::from_bytes(&data).unwrap(), params);
+
+        assert!(PassphraseParams::from_bytes(&data[1..]).is_err());
+
+        let mut expensive = data.clone();
+        expensive[MAGIC.len() + 8] = 0xff;
+        assert!(PassphraseParams::from_bytes(&expensive).is_err());
+    }
+}
@@ -43,6 +43,78 @@ macro_rules! derive_serde {
     };
 }
 
+/// Serializes `data` as `header` and then base64 in human readable formats,
+/// and as plain bytes in binary ones.
+pub(crate) fn serialize_tagged_bytes<S>(
+    serializer: S,
+    header: &str,
+    data: &[u8],
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::ser::Serializer,
+{
+    if serializer.is_human_readable() {
+        serializer.serialize_str(&format!("{}{}", header, base64::encode(data)))
+    } else {
+        serializer.serialize_bytes(data)
+    }
+}
+
+/// Reads back what `serialize_tagged_bytes` wrote.
+pub(crate) fn deserialize_tagged_bytes<'de, D>(
+    deserializer: D,
+    header: &'static str,
+) -> Result<Vec<u8>, D::Error>
+where
+    D: serde::de::Deserializer<'de>,
+{
+    if deserializer.is_human_readable() {
+        deserializer.deserialize_str(TaggedBytesVisitor(header))
+    } else {
+        deserializer.deserialize_byte_buf(TaggedBytesVisitor(header))
+    }
+}
+
+/// Decodes a string written by `serialize_tagged_bytes`.
+pub(crate) fn decode_tagged_str(data: &str, header: &str) -> anyhow::Result<Vec<u8>> {
+    use anyhow::Context;
+    let encoded = data
+        .strip_prefix(header)
+        .with_context(|| format!("expected a string starting with {}", header))?;
+    base64::decode(encoded).with_context(|| format!("invalid base64 after {}", header))
+}
+
+struct TaggedBytesVisitor(&'static str);
+
+impl<'de> serde::de::Visitor<'de> for TaggedBytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(formatter, "bytes, or a string starting with {}", self.0)
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        decode_tagged_str(value, self.0).map_err(|e| E::custom(format!("{:#}", e)))
+    }
+
+    fn visit_bytes<E>(self, value: &[u8]) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(value.to_vec())
+    }
+
+    fn visit_byte_buf<E>(self, value: Vec<u8>) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(value)
+    }
+}
+
 #[cfg(test)]
 macro_rules! test_derive_serde {
     ($key:ty) => {
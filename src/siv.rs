@@ -0,0 +1,332 @@
+use aes::Aes256;
+use anyhow::{Error, Result};
+use cmac::{Cmac, Mac};
+use ctr::cipher::{KeyIvInit, StreamCipher};
+
+use crate::key_util::*;
+
+const BLOCK_LEN: usize = 16;
+const KEY_LEN: usize = 64;
+
+// S2V takes at most this many strings, the plaintext included.
+const MAX_COMPONENTS: usize = 127;
+
+/// Deterministic authenticated encryption of byte strings of any length with
+/// AES-SIV (RFC 5297, with AES-256). Equal plaintexts with equal associated
+/// data encrypt to equal ciphertexts, 16 bytes longer than the plaintext, and
+/// decryption fails if the ciphertext or associated data has been changed.
+///
+/// Unlike `DeterministicEncryptionSymmetricKey256`, nothing needs padding, and
+/// every ciphertext bit depends on the whole plaintext. Deterministic
+/// encryption still reveals which values are equal; use associated data (a
+/// column name, say) to keep equal values in different places apart.
+#[derive(Clone)]
+pub struct DeterministicAeadKey {
+    key: [u8; KEY_LEN],
+}
+
+crate::serde_support::derive_serde!(DeterministicAeadKey, DeterministicAeadKeyVisitor);
+
+impl KeyMaterial for DeterministicAeadKey {
+    const HEADER: &'static str = "eseb1::aes_siv::";
+    fn key_bytes(&self) -> Vec<u8> {
+        self.key.to_vec()
+    }
+}
+
+impl DerivableKey for DeterministicAeadKey {
+    const KDF_CONTEXT: &'static str = "esebsiv";
+    const DERIVED_LEN: usize = KEY_LEN;
+    fn from_derived(data: &[u8]) -> Result<DeterministicAeadKey> {
+        Self::from_slice(data)
+    }
+}
+
+impl std::str::FromStr for DeterministicAeadKey {
+    type Err = anyhow::Error;
+    fn from_str(data: &str) -> Result<DeterministicAeadKey> {
+        let key_data = parse_header(data.trim(), &Self::HEADER)?;
+        Self::from_slice(&key_data)
+    }
+}
+
+impl DeterministicAeadKey {
+    pub fn gen_key() -> Result<DeterministicAeadKey> {
+        Self::from_slice(&sodiumoxide::randombytes::randombytes(KEY_LEN))
+    }
+
+    /// Returns the 16-byte synthetic IV followed by the encrypted plaintext.
+    pub fn encrypt(&self, plaintext: &[u8], associated_data: &[&[u8]]) -> Result<Vec<u8>> {
+        let (mac_key, ctr_key) = self.key.split_at(KEY_LEN / 2);
+        siv_encrypt::<Cmac<Aes256>, ctr::Ctr128BE<Aes256>>(
+            mac_key,
+            ctr_key,
+            plaintext,
+            associated_data,
+        )
+    }
+
+    /// Fails unless `ciphertext` came from `encrypt` with this key and the
+    /// same associated data.
+    pub fn decrypt(&self, ciphertext: &[u8], associated_data: &[&[u8]]) -> Result<Vec<u8>> {
+        let (mac_key, ctr_key) = self.key.split_at(KEY_LEN / 2);
+        siv_decrypt::<Cmac<Aes256>, ctr::Ctr128BE<Aes256>>(
+            mac_key,
+            ctr_key,
+            ciphertext,
+            associated_data,
+        )
+    }
+
+    fn from_slice(slice: &[u8]) -> Result<DeterministicAeadKey> {
+        check_length(slice, KEY_LEN)?;
+        Ok(DeterministicAeadKey {
+            key: slice.try_into().expect("checked length"),
+        })
+    }
+}
+
+// Doubling in GF(2^128), as in CMAC.
+fn dbl(block: &mut [u8; BLOCK_LEN]) {
+    let carry = block[0] >> 7;
+    for i in 0..BLOCK_LEN - 1 {
+        block[i] = block[i] << 1 | block[i + 1] >> 7;
+    }
+    block[BLOCK_LEN - 1] = block[BLOCK_LEN - 1] << 1 ^ (0x87 & 0u8.wrapping_sub(carry));
+}
+
+fn mac<M: Mac + Clone>(mac: &M, data: &[u8]) -> [u8; BLOCK_LEN] {
+    let mut mac = mac.clone();
+    mac.update(data);
+    mac.finalize()
+        .into_bytes()
+        .as_slice()
+        .try_into()
+        .expect("CMAC output is one block")
+}
+
+fn s2v<M: Mac + cmac::digest::KeyInit + Clone>(
+    key: &[u8],
+    associated_data: &[&[u8]],
+    plaintext: &[u8],
+) -> Result<[u8; BLOCK_LEN]> {
+    if associated_data.len() + 1 > MAX_COMPONENTS {
+        anyhow::bail!("at most {} associated data strings", MAX_COMPONENTS - 1);
+    }
+    let cmac = <M as Mac>::new_from_slice(key).map_err(|_| Error::msg("bad CMAC key length"))?;
+
+    let mut d = mac(&cmac, &[0; BLOCK_LEN]);
+    for data in associated_data {
+        dbl(&mut d);
+        for (d, m) in d.iter_mut().zip(mac(&cmac, data)) {
+            *d ^= m;
+        }
+    }
+
+    let mut last = cmac;
+    if plaintext.len() >= BLOCK_LEN {
+        // The plaintext with d xored into its final block.
+        let (head, tail) = plaintext.split_at(plaintext.len() - BLOCK_LEN);
+        last.update(head);
+        let mut tail: [u8; BLOCK_LEN] = tail.try_into().expect("one block");
+        for (t, d) in tail.iter_mut().zip(d) {
+            *t ^= d;
+        }
+        last.update(&tail);
+    } else {
+        dbl(&mut d);
+        for (d, p) in d.iter_mut().zip(plaintext) {
+            *d ^= p;
+        }
+        d[plaintext.len()] ^= 0x80;
+        last.update(&d);
+    }
+    Ok(last
+        .finalize()
+        .into_bytes()
+        .as_slice()
+        .try_into()
+        .expect("CMAC output is one block"))
+}
+
+// The synthetic IV with the two bits cleared that RFC 5297 clears so that
+// implementations may use 32 or 64-bit counter arithmetic.
+fn ctr_iv(v: &[u8; BLOCK_LEN]) -> [u8; BLOCK_LEN] {
+    let mut iv = *v;
+    iv[8] &= 0x7f;
+    iv[12] &= 0x7f;
+    iv
+}
+
+fn siv_encrypt<M, S>(
+    mac_key: &[u8],
+    ctr_key: &[u8],
+    plaintext: &[u8],
+    associated_data: &[&[u8]],
+) -> Result<Vec<u8>>
+where
+    M: Mac + cmac::digest::KeyInit + Clone,
+    S: KeyIvInit + StreamCipher,
+{
+    let v = s2v::<M>(mac_key, associated_data, plaintext)?;
+    let mut out = Vec::with_capacity(BLOCK_LEN + plaintext.len());
+    out.extend_from_slice(&v);
+    out.extend_from_slice(plaintext);
+    S::new_from_slices(ctr_key, &ctr_iv(&v))
+        .map_err(|_| Error::msg("bad CTR key length"))?
+        .apply_keystream(&mut out[BLOCK_LEN..]);
+    Ok(out)
+}
+
+fn siv_decrypt<M, S>(
+    mac_key: &[u8],
+    ctr_key: &[u8],
+    ciphertext: &[u8],
+    associated_data: &[&[u8]],
+) -> Result<Vec<u8>>
+where
+    M: Mac + cmac::digest::KeyInit + Clone,
+    S: KeyIvInit + StreamCipher,
+{
+    if ciphertext.len() < BLOCK_LEN {
+        anyhow::bail!("ciphertext too short");
+    }
+    let (v, ciphertext) = ciphertext.split_at(BLOCK_LEN);
+    let v: [u8; BLOCK_LEN] = v.try_into().expect("one block");
+    let mut plaintext = ciphertext.to_vec();
+    S::new_from_slices(ctr_key, &ctr_iv(&v))
+        .map_err(|_| Error::msg("bad CTR key length"))?
+        .apply_keystream(&mut plaintext);
+
+    let expected = s2v::<M>(mac_key, associated_data, &plaintext)?;
+    if !sodiumoxide::utils::memcmp(&expected, &v) {
+        sodiumoxide::utils::memzero(&mut plaintext);
+        anyhow::bail!("decryption failed: wrong key, associated data or corrupt ciphertext");
+    }
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use aes::Aes128;
+
+    fn rfc_encrypt(key: &str, associated_data: &[&str], plaintext: &str) -> String {
+        let key = hex::decode(key).unwrap();
+        let associated_data: Vec<Vec<u8>> = associated_data
+            .iter()
+            .map(|data| hex::decode(data).unwrap())
+            .collect();
+        let associated_data: Vec<&[u8]> = associated_data.iter().map(|data| &data[..]).collect();
+        let plaintext = hex::decode(plaintext).unwrap();
+        let (mac_key, ctr_key) = key.split_at(16);
+        let ciphertext = siv_encrypt::<Cmac<Aes128>, ctr::Ctr128BE<Aes128>>(
+            mac_key,
+            ctr_key,
+            &plaintext,
+            &associated_data,
+        )
+        .unwrap();
+        let decrypted = siv_decrypt::<Cmac<Aes128>, ctr::Ctr128BE<Aes128>>(
+            mac_key,
+            ctr_key,
+            &ciphertext,
+            &associated_data,
+        )
+        .unwrap();
+        assert_eq!(decrypted, plaintext);
+        hex::encode(ciphertext)
+    }
+
+    // RFC 5297 appendix A, which uses AES-128.
+    #[test]
+    fn test_rfc_vectors() {
+        assert_eq!(
+            rfc_encrypt(
+                "fffefdfcfbfaf9f8f7f6f5f4f3f2f1f0f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff",
+                &["101112131415161718191a1b1c1d1e1f2021222324252627"],
+                "112233445566778899aabbccddee",
+            ),
+            "85632d07c6e8f37f950acd320a2ecc9340c02b9690c4dc04daef7f6afe5c"
+        );
+        assert_eq!(
+            rfc_encrypt(
+                "7f7e7d7c7b7a79787776757473727170404142434445464748494a4b4c4d4e4f",
+                &[
+                    "00112233445566778899aabbccddeeffdeaddadadeaddadaffeeddccbbaa99887766554433221100",
+                    "102030405060708090a0",
+                    "09f911029d74e35bd84156c5635688c0",
+                ],
+                "7468697320697320736f6d6520706c61696e7465787420746f20656e6372797074207573696e67205349562d414553",
+            ),
+            "7bdb6e3b432667eb06f4d14bff2fbd0fcb900f2fddbe404326601965c889bf17\
+             dba77ceb094fa663b7a3f748ba8af829ea64ad544a272e9c485b62a3fd5c0d"
+        );
+    }
+
+    // AES-256 vectors, checked against the `cryptography` Python package.
+    #[test]
+    fn test_known_answers() {
+        let key_bytes: Vec<u8> = (0..64).collect();
+        let key = DeterministicAeadKey::from_slice(&key_bytes).unwrap();
+        for (plaintext, associated_data, expected) in [
+            (
+                &b"alice@example.com"[..],
+                &[&b"users.email"[..]][..],
+                "af56d0877da64e7c41b7bcc252132e39989f6f072b241d94bea43c9c5b02441241",
+            ),
+            (b"", &[], "d4fc53b9c44c2aeea87bfb8c983b136c"),
+            (
+                b"exactly sixteen!",
+                &[b"a", b"b"],
+                "f6fec57d69d16b4a00c90f98d0fc5ca8852808ea334ee7d18abb2ae46409a798",
+            ),
+        ] {
+            let ciphertext = key.encrypt(plaintext, associated_data).unwrap();
+            assert_eq!(hex::encode(&ciphertext), expected);
+            assert_eq!(
+                key.decrypt(&ciphertext, associated_data).unwrap(),
+                plaintext
+            );
+        }
+    }
+
+    #[test]
+    fn test_tampering() {
+        let key = DeterministicAeadKey::gen_key().unwrap();
+        let ciphertext = key.encrypt(b"customer 1234", &[b"ids"]).unwrap();
+        assert_eq!(
+            ciphertext,
+            key.encrypt(b"customer 1234", &[b"ids"]).unwrap()
+        );
+        assert_ne!(
+            ciphertext,
+            key.encrypt(b"customer 1234", &[b"other"]).unwrap()
+        );
+
+        for i in 0..ciphertext.len() {
+            let mut bad = ciphertext.clone();
+            bad[i] ^= 1;
+            assert!(key.decrypt(&bad, &[b"ids"]).is_err());
+        }
+        assert!(key.decrypt(&ciphertext, &[b"other"]).is_err());
+        assert!(key.decrypt(&ciphertext, &[]).is_err());
+        assert!(key.decrypt(&ciphertext[..15], &[b"ids"]).is_err());
+        assert!(DeterministicAeadKey::gen_key()
+            .unwrap()
+            .decrypt(&ciphertext, &[b"ids"])
+            .is_err());
+    }
+
+    #[test]
+    fn test_key_serialization() {
+        let key = DeterministicAeadKey::gen_key().unwrap();
+        let deser = DeterministicAeadKey::from_str(&key.serialize_to_string()).unwrap();
+        assert_eq!(deser.key_bytes(), key.key_bytes());
+    }
+
+    crate::serde_support::test_derive_serde!(DeterministicAeadKey);
+}
@@ -18,7 +18,7 @@ impl std::str::FromStr for SnowKeyPair {
     type Err = anyhow::Error;
     fn from_str(data: &str) -> Result<SnowKeyPair> {
         let mut key_data = parse_header(data.trim(), &Self::HEADER)?;
-        assert_eq!(key_data.len(), 32 * 3);
+        check_length(&key_data, 32 * 3)?;
         let private = key_data.split_off(64);
         let psk = key_data.split_off(32);
         let public = key_data;
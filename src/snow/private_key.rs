@@ -1,6 +1,6 @@
 use anyhow::Result;
 
-use crate::key_util::{parse_header, KeyMaterial};
+use crate::key_util::{check_length, parse_header, KeyMaterial};
 
 #[derive(Clone)]
 pub struct SnowPrivateKey {
@@ -13,7 +13,7 @@ impl std::str::FromStr for SnowPrivateKey {
     type Err = anyhow::Error;
     fn from_str(data: &str) -> Result<SnowPrivateKey> {
         let key_data = parse_header(data.trim(), &Self::HEADER)?;
-        assert_eq!(key_data.len(), 32);
+        check_length(&key_data, 32)?;
         Ok(SnowPrivateKey { key: key_data })
     }
 }
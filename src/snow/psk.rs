@@ -2,7 +2,7 @@ use std::io::Read;
 
 use anyhow::Result;
 
-use crate::key_util::{parse_header, KeyMaterial};
+use crate::key_util::{check_length, parse_header, DerivableKey, KeyMaterial, KeyParseError};
 
 #[derive(Clone)]
 pub struct SnowPsk {
@@ -17,22 +17,23 @@ impl std::str::FromStr for SnowPsk {
         let enc_data = parse_header(data.trim(), &Self::HEADER)?;
         let mut decompressor = brotli::reader::Decompressor::new(&*enc_data, 8192);
         let mut data = Vec::default();
-        decompressor.read_to_end(&mut data)?;
-        if data.len() != 32 {
-            anyhow::bail!("Bad preshared key length. Should be 32 bytes.");
-        }
-        Ok(SnowPsk { data })
+        decompressor
+            .read_to_end(&mut data)
+            .map_err(|e| KeyParseError::InvalidPayload(format!("decompress: {}", e)))?;
+        SnowPsk::new(data)
     }
 }
 
 impl SnowPsk {
     pub fn new(data: Vec<u8>) -> Result<SnowPsk> {
-        if data.len() != 32 {
-            anyhow::bail!("Bad preshared key length. Should be 32 bytes.");
-        }
+        check_length(&data, 32)?;
         Ok(SnowPsk { data })
     }
 
+    pub fn gen_key() -> Result<SnowPsk> {
+        SnowPsk::new(sodiumoxide::randombytes::randombytes(32))
+    }
+
     pub fn from_file(path: &std::path::Path) -> Result<SnowPsk> {
         SnowPsk::new(std::fs::read(path)?)
     }
@@ -42,6 +43,14 @@ impl SnowPsk {
     }
 }
 
+impl DerivableKey for SnowPsk {
+    const KDF_CONTEXT: &'static str = "esebpsk";
+    const DERIVED_LEN: usize = 32;
+    fn from_derived(data: &[u8]) -> Result<SnowPsk> {
+        SnowPsk::new(data.to_vec())
+    }
+}
+
 impl KeyMaterial for SnowPsk {
     const HEADER: &'static str = "eseb1::snow_preshared_key::";
     fn key_bytes(&self) -> Vec<u8> {
@@ -1,6 +1,6 @@
 use anyhow::Result;
 
-use crate::key_util::{parse_header, KeyMaterial};
+use crate::key_util::{check_length, parse_header, KeyMaterial};
 
 // Note that this is generally useless without the psk. This library is geared
 // toward using Snow via symmetric encryption, so both sides have the full key
@@ -16,7 +16,7 @@ impl std::str::FromStr for SnowPublicKey {
     type Err = anyhow::Error;
     fn from_str(data: &str) -> Result<SnowPublicKey> {
         let key_data = parse_header(data.trim(), &Self::HEADER)?;
-        assert_eq!(key_data.len(), 32);
+        check_length(&key_data, 32)?;
         Ok(SnowPublicKey { key: key_data })
     }
 }
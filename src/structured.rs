@@ -0,0 +1,525 @@
+use anyhow::{Context, Error, Result};
+use serde_json::{Map, Value};
+use sodiumoxide::crypto::aead::xchacha20poly1305_ietf as aead;
+use sodiumoxide::crypto::generichash;
+
+use crate::key_util::KeyMaterial;
+use crate::SymmetricKey;
+
+const VALUE_HEADER: &str = "eseb1::enc::";
+
+// Top level key holding the version and MAC of an encrypted document.
+const METADATA_KEY: &str = "eseb";
+const VERSION: u64 = 1;
+
+// Subkey contexts, so that the same `SymmetricKey` can also encrypt files.
+const VALUE_CONTEXT: &str = "esebsval";
+const MAC_CONTEXT: &str = "esebsmac";
+const MAC_LEN: usize = 32;
+
+// How a TOML datetime appears in the document, as other formats have none.
+// It is a leaf, not a map.
+const TOML_DATETIME_KEY: &str = "$__toml_private_datetime";
+
+/// A document format that `structured_encrypt` understands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StructuredFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl std::str::FromStr for StructuredFormat {
+    type Err = anyhow::Error;
+    fn from_str(data: &str) -> Result<StructuredFormat> {
+        match data {
+            "json" => Ok(StructuredFormat::Json),
+            "yaml" | "yml" => Ok(StructuredFormat::Yaml),
+            "toml" => Ok(StructuredFormat::Toml),
+            _ => anyhow::bail!("unknown document format {:?}", data),
+        }
+    }
+}
+
+impl StructuredFormat {
+    /// Guesses the format from a file name's extension.
+    pub fn from_path<P: AsRef<std::path::Path>>(path: P) -> Option<StructuredFormat> {
+        path.as_ref().extension()?.to_str()?.parse().ok()
+    }
+
+    pub fn parse(self, data: &str) -> Result<Value> {
+        Ok(match self {
+            StructuredFormat::Json => serde_json::from_str(data).context("parse JSON")?,
+            StructuredFormat::Yaml => {
+                let doc: serde_yaml::Value = serde_yaml::from_str(data).context("parse YAML")?;
+                check_yaml_floats(&doc, &mut String::default())?;
+                serde_yaml::from_value(doc).context("parse YAML")?
+            }
+            StructuredFormat::Toml => from_toml(
+                toml::from_str::<toml::Table>(data)
+                    .context("parse TOML")?
+                    .into(),
+                &mut String::default(),
+            )?,
+        })
+    }
+
+    pub fn print(self, doc: &Value) -> Result<String> {
+        Ok(match self {
+            StructuredFormat::Json => serde_json::to_string_pretty(doc)? + "\n",
+            StructuredFormat::Yaml => serde_yaml::to_string(doc)?,
+            StructuredFormat::Toml => toml::to_string(&to_toml(doc.clone())?)?,
+        })
+    }
+}
+
+/// Encrypts the values of a JSON, YAML or TOML document, leaving its keys and
+/// structure readable, so that a diff shows which values changed. See
+/// `structured_encrypt_value`. Comments and formatting are not kept.
+pub fn structured_encrypt(
+    key: &SymmetricKey,
+    data: &str,
+    format: StructuredFormat,
+) -> Result<String> {
+    let mut doc = format.parse(data)?;
+    structured_encrypt_value(key, &mut doc)?;
+    format.print(&doc)
+}
+
+pub fn structured_decrypt(
+    key: &SymmetricKey,
+    data: &str,
+    format: StructuredFormat,
+) -> Result<String> {
+    let mut doc = format.parse(data)?;
+    structured_decrypt_value(key, &mut doc)?;
+    format.print(&doc)
+}
+
+/// Replaces every leaf of `doc`, which must be a map, with an `eseb1::enc::`
+/// string. Each leaf is encrypted with its path as associated data, so it
+/// cannot be moved elsewhere, and a MAC over all of them and the path and kind
+/// of every map and list, stored under the top level key `eseb`, catches
+/// leaves that are removed, added or swapped, and any change to the
+/// structure, even where there are no leaves.
+pub fn structured_encrypt_value(key: &SymmetricKey, doc: &mut Value) -> Result<()> {
+    let root = doc
+        .as_object()
+        .context("the top level of the document must be a map")?;
+    if root.contains_key(METADATA_KEY) {
+        anyhow::bail!(
+            "the document is already encrypted, or uses the reserved top level key {:?}",
+            METADATA_KEY
+        );
+    }
+    let keys = Keys::new(key)?;
+    let mut mac = keys.mac();
+    update_mac_containers(&mut mac, doc, &mut String::default())?;
+    walk(doc, &mut String::default(), &mut |path, leaf| {
+        let mut cleartext = serde_json::to_vec(leaf)?;
+        update_mac(&mut mac, path, &cleartext)?;
+        let nonce = aead::gen_nonce();
+        let mut v = nonce.as_ref().to_vec();
+        v.extend(aead::seal(
+            &cleartext,
+            Some(path.as_bytes()),
+            &nonce,
+            &keys.value,
+        ));
+        sodiumoxide::utils::memzero(&mut cleartext);
+        *leaf = Value::String(format!("{}{}", VALUE_HEADER, base64::encode(v)));
+        Ok(())
+    })?;
+    let mut metadata = Map::default();
+    metadata.insert("version".to_string(), VERSION.into());
+    metadata.insert("mac".to_string(), finish_mac(mac)?.into());
+    doc.as_object_mut()
+        .expect("checked map")
+        .insert(METADATA_KEY.to_string(), metadata.into());
+    Ok(())
+}
+
+/// Reverses `structured_encrypt_value`. `doc` is only changed if every leaf
+/// decrypts and the MAC matches.
+pub fn structured_decrypt_value(key: &SymmetricKey, doc: &mut Value) -> Result<()> {
+    let mut decrypted = doc.clone();
+    let metadata = decrypted
+        .as_object_mut()
+        .context("the top level of the document must be a map")?
+        .remove(METADATA_KEY)
+        .with_context(|| format!("not encrypted: no top level {:?} key", METADATA_KEY))?;
+    let version = metadata.get("version").and_then(Value::as_u64);
+    if version != Some(VERSION) {
+        anyhow::bail!("unsupported encrypted document version {:?}", version);
+    }
+    let expected_mac = metadata
+        .get("mac")
+        .and_then(Value::as_str)
+        .context("encrypted document has no MAC")?;
+
+    let keys = Keys::new(key)?;
+    let mut mac = keys.mac();
+    update_mac_containers(&mut mac, &decrypted, &mut String::default())?;
+    walk(&mut decrypted, &mut String::default(), &mut |path, leaf| {
+        let data = leaf
+            .as_str()
+            .and_then(|s| s.strip_prefix(VALUE_HEADER))
+            .and_then(|s| base64::decode(s).ok())
+            .filter(|data| data.len() >= aead::NONCEBYTES + aead::TAGBYTES)
+            .with_context(|| format!("{}: not an encrypted value", path))?;
+        let (nonce, ciphertext) = data.split_at(aead::NONCEBYTES);
+        let nonce = aead::Nonce::from_slice(nonce).expect("checked length");
+        let mut cleartext = aead::open(ciphertext, Some(path.as_bytes()), &nonce, &keys.value)
+            .map_err(|_| Error::msg(format!("{}: failed authentication", path)))?;
+        update_mac(&mut mac, path, &cleartext)?;
+        let value = serde_json::from_slice(&cleartext);
+        sodiumoxide::utils::memzero(&mut cleartext);
+        *leaf = value.with_context(|| format!("{}: bad encrypted value", path))?;
+        Ok(())
+    })?;
+    let mac = finish_mac(mac)?;
+    if !sodiumoxide::utils::memcmp(mac.as_bytes(), expected_mac.as_bytes()) {
+        anyhow::bail!(
+            "document MAC does not match: values or structure were removed, added or reordered"
+        );
+    }
+    *doc = decrypted;
+    Ok(())
+}
+
+struct Keys {
+    value: aead::Key,
+    mac: SymmetricKey,
+}
+
+impl Keys {
+    fn new(key: &SymmetricKey) -> Result<Keys> {
+        let value = key.derive(VALUE_CONTEXT, 0)?;
+        Ok(Keys {
+            value: aead::Key::from_slice(&value.key_bytes()).expect("same key length"),
+            mac: key.derive(MAC_CONTEXT, 0)?,
+        })
+    }
+
+    fn mac(&self) -> generichash::State {
+        generichash::State::new(Some(MAC_LEN), Some(&self.mac.key_bytes()))
+            .expect("valid blake2b parameters")
+    }
+}
+
+fn update_mac(mac: &mut generichash::State, path: &str, cleartext: &[u8]) -> Result<()> {
+    for part in [path.as_bytes(), cleartext] {
+        mac.update(&(part.len() as u64).to_le_bytes())
+            .and_then(|()| mac.update(part))
+            .map_err(|_| Error::msg("hash document"))?;
+    }
+    Ok(())
+}
+
+// Adds every map and list, in document order, to the MAC, so that ones
+// without leaves are covered too. A leaf's cleartext is never `{}` or `[]`,
+// as those are not leaves.
+fn update_mac_containers(
+    mac: &mut generichash::State,
+    value: &Value,
+    path: &mut String,
+) -> Result<()> {
+    let len = path.len();
+    match value {
+        Value::Object(map) if !is_toml_datetime(map) => {
+            update_mac(mac, path, b"{}")?;
+            for (name, child) in map {
+                push_name(path, name);
+                update_mac_containers(mac, child, path)?;
+                path.truncate(len);
+            }
+        }
+        Value::Array(items) => {
+            update_mac(mac, path, b"[]")?;
+            for (i, child) in items.iter().enumerate() {
+                push_name(path, &i.to_string());
+                update_mac_containers(mac, child, path)?;
+                path.truncate(len);
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn finish_mac(mac: generichash::State) -> Result<String> {
+    let digest = mac.finalize().map_err(|_| Error::msg("hash document"))?;
+    Ok(hex::encode(digest.as_ref()))
+}
+
+// Calls `f` on every leaf in document order, with its path as a JSON pointer
+// (RFC 6901), e.g. `/servers/0/password`.
+fn walk<F>(value: &mut Value, path: &mut String, f: &mut F) -> Result<()>
+where
+    F: FnMut(&str, &mut Value) -> Result<()>,
+{
+    let len = path.len();
+    match value {
+        Value::Object(map) if !is_toml_datetime(map) => {
+            for (name, child) in map.iter_mut() {
+                push_name(path, name);
+                walk(child, path, f)?;
+                path.truncate(len);
+            }
+        }
+        Value::Array(items) => {
+            for (i, child) in items.iter_mut().enumerate() {
+                path.push('/');
+                path.push_str(&i.to_string());
+                walk(child, path, f)?;
+                path.truncate(len);
+            }
+        }
+        leaf => f(path, leaf)?,
+    }
+    Ok(())
+}
+
+fn push_name(path: &mut String, name: &str) {
+    path.push('/');
+    path.push_str(&name.replace('~', "~0").replace('/', "~1"));
+}
+
+// JSON has no NaN or infinity; serde_json would quietly turn them into null,
+// which TOML cannot even print back.
+fn check_finite(f: f64, path: &str) -> Result<()> {
+    if !f.is_finite() {
+        anyhow::bail!("{}: only finite numbers can be encrypted, not {}", path, f);
+    }
+    Ok(())
+}
+
+fn check_yaml_floats(value: &serde_yaml::Value, path: &mut String) -> Result<()> {
+    let len = path.len();
+    match value {
+        serde_yaml::Value::Number(n) => check_finite(n.as_f64().unwrap_or_default(), path)?,
+        serde_yaml::Value::Sequence(items) => {
+            for (i, child) in items.iter().enumerate() {
+                push_name(path, &i.to_string());
+                check_yaml_floats(child, path)?;
+                path.truncate(len);
+            }
+        }
+        serde_yaml::Value::Mapping(map) => {
+            for (name, child) in map {
+                push_name(path, name.as_str().unwrap_or("?"));
+                check_yaml_floats(child, path)?;
+                path.truncate(len);
+            }
+        }
+        serde_yaml::Value::Tagged(tagged) => check_yaml_floats(&tagged.value, path)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+fn is_toml_datetime(map: &Map<String, Value>) -> bool {
+    map.len() == 1 && map.contains_key(TOML_DATETIME_KEY)
+}
+
+fn from_toml(value: toml::Value, path: &mut String) -> Result<Value> {
+    let len = path.len();
+    Ok(match value {
+        toml::Value::String(s) => s.into(),
+        toml::Value::Integer(i) => i.into(),
+        toml::Value::Float(f) => {
+            check_finite(f, path)?;
+            f.into()
+        }
+        toml::Value::Boolean(b) => b.into(),
+        toml::Value::Datetime(d) => {
+            let mut map = Map::default();
+            map.insert(TOML_DATETIME_KEY.to_string(), d.to_string().into());
+            map.into()
+        }
+        toml::Value::Array(items) => items
+            .into_iter()
+            .enumerate()
+            .map(|(i, item)| {
+                push_name(path, &i.to_string());
+                let item = from_toml(item, path);
+                path.truncate(len);
+                item
+            })
+            .collect::<Result<_>>()?,
+        toml::Value::Table(table) => table
+            .into_iter()
+            .map(|(name, value)| {
+                push_name(path, &name);
+                let value = from_toml(value, path);
+                path.truncate(len);
+                Ok((name, value?))
+            })
+            .collect::<Result<Map<_, _>>>()?
+            .into(),
+    })
+}
+
+fn to_toml(value: Value) -> Result<toml::Value> {
+    Ok(match value {
+        Value::Null => anyhow::bail!("TOML has no null"),
+        Value::Bool(b) => b.into(),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => i.into(),
+            None => n.as_f64().context("number out of range for TOML")?.into(),
+        },
+        Value::String(s) => s.into(),
+        Value::Array(items) => items
+            .into_iter()
+            .map(to_toml)
+            .collect::<Result<Vec<_>>>()?
+            .into(),
+        Value::Object(map) if is_toml_datetime(&map) => {
+            let d = map[TOML_DATETIME_KEY]
+                .as_str()
+                .context("bad TOML datetime")?;
+            toml::Value::Datetime(d.parse().context("bad TOML datetime")?)
+        }
+        Value::Object(map) => map
+            .into_iter()
+            .map(|(name, value)| Ok((name, to_toml(value)?)))
+            .collect::<Result<toml::Table>>()?
+            .into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const YAML: &str = "\
+db:
+  host: db.example.com
+  password: hunter2
+  port: 5432
+replicas:
+- name: a
+  weight: 0.5
+- name: b
+  weight: 1.5
+debug: false
+empty: {}
+";
+
+    #[test]
+    fn test_round_trip() {
+        let key = SymmetricKey::gen_key().unwrap();
+        let encrypted = structured_encrypt(&key, YAML, StructuredFormat::Yaml).unwrap();
+        assert!(!encrypted.contains("hunter2"));
+        assert!(!encrypted.contains("5432"));
+        let doc: Value = serde_yaml::from_str(&encrypted).unwrap();
+        assert!(doc["db"]["password"]
+            .as_str()
+            .unwrap()
+            .starts_with(VALUE_HEADER));
+        assert_eq!(doc["replicas"].as_array().unwrap().len(), 2);
+        assert_eq!(doc["empty"], Value::Object(Map::default()));
+        assert_eq!(
+            structured_decrypt(&key, &encrypted, StructuredFormat::Yaml).unwrap(),
+            YAML
+        );
+
+        let other = SymmetricKey::gen_key().unwrap();
+        assert!(structured_decrypt(&other, &encrypted, StructuredFormat::Yaml).is_err());
+        assert!(structured_encrypt(&key, &encrypted, StructuredFormat::Yaml).is_err());
+    }
+
+    #[test]
+    fn test_json_and_toml() {
+        let key = SymmetricKey::gen_key().unwrap();
+        let json = "{\n  \"b\": [\n    1,\n    null,\n    \"x\"\n  ],\n  \"a\": {\n    \"c~/d\": true\n  }\n}\n";
+        let encrypted = structured_encrypt(&key, json, StructuredFormat::Json).unwrap();
+        assert_eq!(
+            structured_decrypt(&key, &encrypted, StructuredFormat::Json).unwrap(),
+            json
+        );
+
+        let toml = "title = \"x\"\nwhen = 1979-05-27T07:32:00Z\n\n[owner]\nname = \"Tom\"\n";
+        let encrypted = structured_encrypt(&key, toml, StructuredFormat::Toml).unwrap();
+        assert!(!encrypted.contains("Tom"));
+        assert!(!encrypted.contains("1979"));
+        assert_eq!(
+            structured_decrypt(&key, &encrypted, StructuredFormat::Toml).unwrap(),
+            toml
+        );
+    }
+
+    #[test]
+    fn test_non_finite_floats() {
+        let key = SymmetricKey::gen_key().unwrap();
+        let err = structured_encrypt(&key, "[a]\nx = inf\n", StructuredFormat::Toml).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "/a/x: only finite numbers can be encrypted, not inf"
+        );
+        assert!(structured_encrypt(&key, "x = [1.5, nan]\n", StructuredFormat::Toml).is_err());
+        assert!(structured_encrypt(&key, "x: [.nan]\n", StructuredFormat::Yaml).is_err());
+        assert!(structured_encrypt(&key, "x: -.inf\n", StructuredFormat::Yaml).is_err());
+    }
+
+    #[test]
+    fn test_tampering() {
+        let key = SymmetricKey::gen_key().unwrap();
+        let mut doc = StructuredFormat::Yaml.parse(YAML).unwrap();
+        structured_encrypt_value(&key, &mut doc).unwrap();
+        let decrypts = |doc: &Value| structured_decrypt_value(&key, &mut doc.clone()).is_ok();
+        assert!(decrypts(&doc));
+
+        // Moving a value to another path.
+        let mut moved = doc.clone();
+        moved["db"]["host"] = doc["db"]["password"].clone();
+        assert!(!decrypts(&moved));
+
+        // Removing a value, or reordering a list.
+        let mut removed = doc.clone();
+        removed["db"].as_object_mut().unwrap().remove("port");
+        assert!(!decrypts(&removed));
+        let mut reordered = doc.clone();
+        reordered["replicas"].as_array_mut().unwrap().reverse();
+        assert!(!decrypts(&reordered));
+
+        // Replacing one encryption of a value with another.
+        let mut other = StructuredFormat::Yaml.parse(YAML).unwrap();
+        structured_encrypt_value(&key, &mut other).unwrap();
+        let mut replaced = doc.clone();
+        replaced["debug"] = other["debug"].clone();
+        assert!(decrypts(&replaced));
+        replaced["eseb"]["mac"] = "00".into();
+        assert!(!decrypts(&replaced));
+
+        // Unencrypted values.
+        let mut plain = doc.clone();
+        plain["debug"] = true.into();
+        assert!(!decrypts(&plain));
+
+        // Adding, removing or changing the kind of empty maps and lists.
+        let mut added = doc.clone();
+        added["db"]["extra"] = Value::Object(Map::default());
+        assert!(!decrypts(&added));
+        let mut removed = doc.clone();
+        removed.as_object_mut().unwrap().remove("empty");
+        assert!(!decrypts(&removed));
+        let mut changed = doc.clone();
+        changed["empty"] = Value::Array(Vec::default());
+        assert!(!decrypts(&changed));
+    }
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(
+            StructuredFormat::from_path("config/prod.yml"),
+            Some(StructuredFormat::Yaml)
+        );
+        assert_eq!(
+            StructuredFormat::from_path("Cargo.toml"),
+            Some(StructuredFormat::Toml)
+        );
+        assert_eq!(StructuredFormat::from_path("notes.txt"), None);
+        assert_eq!(StructuredFormat::from_path("json"), None);
+    }
+}
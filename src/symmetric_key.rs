@@ -1,5 +1,5 @@
 use anyhow::{Error, Result};
-use sodiumoxide::crypto::secretstream;
+use sodiumoxide::crypto::{generichash, kdf, secretstream};
 
 use crate::key_util::*;
 
@@ -14,9 +14,7 @@ impl std::str::FromStr for SymmetricKey {
     type Err = anyhow::Error;
     fn from_str(data: &str) -> Result<SymmetricKey> {
         let key_data = parse_header(data.trim(), &Self::HEADER)?;
-        let key = secretstream::xchacha20poly1305::Key::from_slice(&key_data)
-            .ok_or_else(|| Error::msg("sodiumoxide returned error attempting to parse the key"))?;
-        Ok(SymmetricKey { key })
+        Self::from_slice(&key_data)
     }
 }
 
@@ -32,6 +30,110 @@ impl SymmetricKey {
             key: secretstream::xchacha20poly1305::gen_key(),
         })
     }
+
+    pub(crate) fn from_slice(key_data: &[u8]) -> Result<SymmetricKey> {
+        check_length(key_data, secretstream::xchacha20poly1305::KEYBYTES)?;
+        let key = secretstream::xchacha20poly1305::Key::from_slice(key_data)
+            .ok_or_else(|| Error::msg("sodiumoxide returned error attempting to parse the key"))?;
+        Ok(SymmetricKey { key })
+    }
+}
+
+// Prefix of the hashed components in `derive_path`.
+const PATH_CONTEXT: &str = "esebpath";
+
+impl SymmetricKey {
+    /// Subkey number `id` of this key: libsodium's
+    /// `crypto_kdf_derive_from_key(id, context)`, a single BLAKE2b KDF call.
+    /// The `context` is at most 8 bytes and names what the subkeys are for,
+    /// e.g. `"backups"`; subkeys with different contexts are unrelated.
+    pub fn derive(&self, context: &str, id: u64) -> Result<SymmetricKey> {
+        self.derive_step(context, id)
+    }
+
+    /// Like `derive`, but produces any `DerivableKey`, in two KDF calls: the
+    /// subkey `derive` returns, then subkey 0 of that with the key type's
+    /// own context, so that e.g. a `SnowPsk` never equals the `SymmetricKey`
+    /// derived with the same arguments. Not the same key as `derive`, even
+    /// for a `SymmetricKey`.
+    pub fn derive_key<K: DerivableKey>(&self, context: &str, id: u64) -> Result<K> {
+        self.derive_step(context, id)?.derive_leaf()
+    }
+
+    /// Derives a key for a `/` separated path of arbitrary names, e.g.
+    /// `"prod/billing/logs"`. Every component is one derivation step, so the
+    /// key for `"prod"` can be handed to whoever manages everything under it.
+    pub fn derive_path<K: DerivableKey>(&self, path: &str) -> Result<K> {
+        let mut key = self.clone();
+        for component in path.split('/') {
+            if component.is_empty() {
+                anyhow::bail!("empty component in key path {:?}", path);
+            }
+            key = key.derive_component(component)?;
+        }
+        key.derive_leaf()
+    }
+
+    // A step of `derive_path`: BLAKE2b keyed with this key over the whole
+    // component. The KDF behind `derive` hashes no message, so no `derive`
+    // call can produce the same key.
+    fn derive_component(&self, component: &str) -> Result<SymmetricKey> {
+        self.derive_keyed(PATH_CONTEXT.as_bytes(), component.as_bytes())
+    }
+
+    /// A subkey for arbitrary `data`: BLAKE2b of `context` and `data`, keyed
+    /// with this key.
+    pub(crate) fn derive_keyed(&self, context: &[u8], data: &[u8]) -> Result<SymmetricKey> {
+        let mut state = generichash::State::new(Some(Self::DERIVED_LEN), Some(self.key.as_ref()))
+            .map_err(|_| Error::msg("init blake2b"))?;
+        state
+            .update(context)
+            .and_then(|()| state.update(data))
+            .map_err(|_| Error::msg("hash subkey data"))?;
+        let digest = state
+            .finalize()
+            .map_err(|_| Error::msg("finalize blake2b"))?;
+        Self::from_slice(digest.as_ref())
+    }
+
+    fn derive_step(&self, context: &str, id: u64) -> Result<SymmetricKey> {
+        let mut data = self.derive_bytes(context, id, Self::DERIVED_LEN)?;
+        let key = Self::from_slice(&data);
+        sodiumoxide::utils::memzero(&mut data);
+        key
+    }
+
+    fn derive_leaf<K: DerivableKey>(&self) -> Result<K> {
+        let mut data = self.derive_bytes(K::KDF_CONTEXT, 0, K::DERIVED_LEN)?;
+        let key = K::from_derived(&data);
+        sodiumoxide::utils::memzero(&mut data);
+        key
+    }
+
+    fn derive_bytes(&self, context: &str, id: u64, len: usize) -> Result<Vec<u8>> {
+        if context.len() > kdf::CONTEXTBYTES {
+            anyhow::bail!(
+                "KDF context {:?} is longer than {} bytes",
+                context,
+                kdf::CONTEXTBYTES
+            );
+        }
+        let mut padded = [0; kdf::CONTEXTBYTES];
+        padded[..context.len()].copy_from_slice(context.as_bytes());
+        let key = kdf::Key::from_slice(self.key.as_ref()).expect("same key length");
+        let mut v = vec![0; len];
+        kdf::derive_from_key(&mut v, id, padded, &key)
+            .map_err(|_| Error::msg(format!("cannot derive a {} byte key", len)))?;
+        Ok(v)
+    }
+}
+
+impl DerivableKey for SymmetricKey {
+    const KDF_CONTEXT: &'static str = "esebsym";
+    const DERIVED_LEN: usize = secretstream::xchacha20poly1305::KEYBYTES;
+    fn from_derived(data: &[u8]) -> Result<SymmetricKey> {
+        Self::from_slice(data)
+    }
 }
 
 impl KeyMaterial for SymmetricKey {
@@ -47,6 +149,8 @@ mod tests {
 
     use std::str::FromStr;
 
+    use crate::{DeterministicEncryptionSymmetricKey256, SnowPsk};
+
     #[test]
     fn test_symmetric_key() {
         let key = SymmetricKey::gen_key().unwrap();
@@ -56,5 +160,69 @@ mod tests {
         assert_eq!(deser_key.key_bytes(), key.key_bytes());
     }
 
+    #[test]
+    fn test_derive() {
+        let key = SymmetricKey::gen_key().unwrap();
+        let a = key.derive("backups", 1).unwrap();
+        assert_eq!(a.key_bytes(), key.derive("backups", 1).unwrap().key_bytes());
+        assert_ne!(a.key_bytes(), key.derive("backups", 2).unwrap().key_bytes());
+        assert_ne!(a.key_bytes(), key.derive("logs", 1).unwrap().key_bytes());
+        assert_ne!(a.key_bytes(), key.key_bytes());
+        assert_ne!(
+            a.key_bytes(),
+            SymmetricKey::gen_key()
+                .unwrap()
+                .derive("backups", 1)
+                .unwrap()
+                .key_bytes()
+        );
+        assert!(key.derive("ninebytes", 1).is_err());
+    }
+
+    #[test]
+    fn test_derive_types() {
+        let key = SymmetricKey::gen_key().unwrap();
+        let sym: SymmetricKey = key.derive_key("db", 7).unwrap();
+        let det: DeterministicEncryptionSymmetricKey256 = key.derive_key("db", 7).unwrap();
+        let psk: SnowPsk = key.derive_key("db", 7).unwrap();
+        assert_ne!(sym.key_bytes(), key.derive("db", 7).unwrap().key_bytes());
+        assert_ne!(&det.key_bytes()[..32], &sym.key_bytes()[..]);
+        assert_ne!(psk.key(), &sym.key_bytes()[..]);
+    }
+
+    #[test]
+    fn test_derive_path() {
+        let key = SymmetricKey::gen_key().unwrap();
+        let logs: SymmetricKey = key.derive_path("prod/billing/logs").unwrap();
+        let again: SymmetricKey = key.derive_path("prod/billing/logs").unwrap();
+        let other: SymmetricKey = key.derive_path("prod/billing/db").unwrap();
+        assert_eq!(logs.key_bytes(), again.key_bytes());
+        assert_ne!(logs.key_bytes(), other.key_bytes());
+
+        let psk: SnowPsk = key.derive_path("prod/billing/logs").unwrap();
+        assert_ne!(psk.key(), &logs.key_bytes()[..]);
+
+        assert!(key.derive_path::<SymmetricKey>("prod//logs").is_err());
+        assert!(key.derive_path::<SymmetricKey>("").is_err());
+    }
+
+    // Derived keys must never change, or keys that were only ever derived
+    // are lost. Checked against Python's hashlib.blake2b.
+    #[test]
+    fn test_derive_stable() {
+        let key: SymmetricKey = "eseb0::sym::AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=::51305"
+            .parse()
+            .unwrap();
+        let derived: SymmetricKey = key.derive_path("prod/billing/logs").unwrap();
+        assert_eq!(
+            hex::encode(derived.key_bytes()),
+            "5869479d553c322baa8bff3ac2a352fe65b08c100a2259257c55541b85ddafab"
+        );
+        assert_eq!(
+            hex::encode(key.derive("backups", 1).unwrap().key_bytes()),
+            "e80526d87bb3f080bd4fd0420d0b10b92a1a362ffd978841d482f8f438b38bab"
+        );
+    }
+
     crate::serde_support::test_derive_serde!(SymmetricKey);
 }
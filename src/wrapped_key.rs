@@ -0,0 +1,154 @@
+use anyhow::{Context, Error, Result};
+use sodiumoxide::crypto::aead::xchacha20poly1305_ietf as aead;
+
+use crate::key_util::*;
+use crate::passphrase::ENCODED_LEN;
+use crate::PassphraseParams;
+
+/// Any serialized key, encrypted under a key derived from a passphrase with
+/// Argon2id. The salt and costs travel with the wrapped key, so only the
+/// passphrase is needed to unwrap it.
+#[derive(Clone)]
+pub struct WrappedKey {
+    params: PassphraseParams,
+    nonce: aead::Nonce,
+    ciphertext: Vec<u8>,
+}
+
+crate::serde_support::derive_serde!(WrappedKey, WrappedKeyVisitor);
+
+impl std::str::FromStr for WrappedKey {
+    type Err = anyhow::Error;
+    fn from_str(data: &str) -> Result<WrappedKey> {
+        let key_data = parse_header(data.trim(), &Self::HEADER)?;
+        if key_data.len() < ENCODED_LEN + aead::NONCEBYTES + aead::TAGBYTES {
+            return Err(KeyParseError::InvalidPayload("wrapped key too short".to_string()).into());
+        }
+        let (params, rest) = key_data.split_at(ENCODED_LEN);
+        let (nonce, ciphertext) = rest.split_at(aead::NONCEBYTES);
+        let params = PassphraseParams::from_bytes(params)
+            .map_err(|e| KeyParseError::InvalidPayload(format!("{:#}", e)))?;
+        Ok(WrappedKey {
+            params,
+            nonce: aead::Nonce::from_slice(nonce).expect("checked length"),
+            ciphertext: ciphertext.to_vec(),
+        })
+    }
+}
+
+impl WrappedKey {
+    pub fn wrap<K: KeyMaterial>(
+        key: &K,
+        passphrase: &[u8],
+        params: &PassphraseParams,
+    ) -> Result<WrappedKey> {
+        Self::wrap_string(&key.serialize_to_string(), passphrase, params)
+    }
+
+    pub(crate) fn wrap_string(
+        key: &str,
+        passphrase: &[u8],
+        params: &PassphraseParams,
+    ) -> Result<WrappedKey> {
+        let wrapping_key = Self::wrapping_key(passphrase, params)?;
+        let nonce = aead::gen_nonce();
+        let ciphertext = aead::seal(
+            key.as_bytes(),
+            Some(&params.to_bytes()),
+            &nonce,
+            &wrapping_key,
+        );
+        Ok(WrappedKey {
+            params: params.clone(),
+            nonce,
+            ciphertext,
+        })
+    }
+
+    pub fn unwrap_key<K>(&self, passphrase: &[u8]) -> Result<K>
+    where
+        K: std::str::FromStr<Err = anyhow::Error>,
+    {
+        self.unwrap_to_string(passphrase)?.parse()
+    }
+
+    /// Returns the serialized inner key, whatever its type.
+    pub fn unwrap_to_string(&self, passphrase: &[u8]) -> Result<String> {
+        let wrapping_key = Self::wrapping_key(passphrase, &self.params)?;
+        let cleartext = aead::open(
+            &self.ciphertext,
+            Some(&self.params.to_bytes()),
+            &self.nonce,
+            &wrapping_key,
+        )
+        .map_err(|_| Error::msg("wrong passphrase or corrupt wrapped key"))?;
+        String::from_utf8(cleartext).context("wrapped key is not a key string")
+    }
+
+    pub fn params(&self) -> &PassphraseParams {
+        &self.params
+    }
+
+    fn wrapping_key(passphrase: &[u8], params: &PassphraseParams) -> Result<aead::Key> {
+        let key = params.derive_key(passphrase)?;
+        aead::Key::from_slice(&key.key_bytes()).context("wrapping key length")
+    }
+}
+
+impl KeyMaterial for WrappedKey {
+    const HEADER: &'static str = "eseb1::wrapped::";
+    fn key_bytes(&self) -> Vec<u8> {
+        let mut v = self.params.to_bytes();
+        v.extend_from_slice(self.nonce.as_ref());
+        v.extend_from_slice(&self.ciphertext);
+        v
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use crate::{SnowKeyPair, SymmetricKey};
+
+    #[test]
+    fn test_wrap_unwrap() {
+        let params = PassphraseParams::interactive().unwrap();
+        let key = SymmetricKey::gen_key().unwrap();
+        let wrapped = WrappedKey::wrap(&key, b"open sesame", &params).unwrap();
+        let ser = wrapped.serialize_to_string();
+        assert!(ser.starts_with(WrappedKey::HEADER));
+        assert!(!ser.contains(&key.serialize_to_string()));
+
+        let wrapped = WrappedKey::from_str(&ser).unwrap();
+        let unwrapped: SymmetricKey = wrapped.unwrap_key(b"open sesame").unwrap();
+        assert_eq!(unwrapped.key_bytes(), key.key_bytes());
+
+        assert!(wrapped.unwrap_key::<SymmetricKey>(b"open sesame!").is_err());
+        assert!(wrapped.unwrap_key::<SnowKeyPair>(b"open sesame").is_err());
+    }
+
+    #[test]
+    fn test_wrap_snow_key_pair() {
+        let params = PassphraseParams::interactive().unwrap();
+        let key = SnowKeyPair::gen_key().unwrap();
+        let wrapped = WrappedKey::wrap(&key, b"pw", &params).unwrap();
+        assert_eq!(
+            wrapped.unwrap_to_string(b"pw").unwrap(),
+            key.serialize_to_string()
+        );
+    }
+
+    #[test]
+    fn test_serde() {
+        let params = PassphraseParams::interactive().unwrap();
+        let key = SymmetricKey::gen_key().unwrap();
+        let wrapped = WrappedKey::wrap(&key, b"pw", &params).unwrap();
+        let ser_key = bincode::serialize(&wrapped).unwrap();
+        assert!(!ser_key.is_empty());
+        let deser_key: WrappedKey = bincode::deserialize(&ser_key).unwrap();
+        assert_eq!(deser_key.key_bytes(), wrapped.key_bytes());
+    }
+}